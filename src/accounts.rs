@@ -0,0 +1,408 @@
+use std::sync::{Arc, Mutex};
+
+use log::error;
+use psn_api_rs::psn::PSN;
+use psn_api_rs::traits::PSNRequest;
+use psn_api_rs::types::PSNInner;
+
+use crate::config::{RefresherConfig, ACCESS_TOKEN_TTL};
+use crate::credentials::{CredentialStore, StoredAccount};
+use crate::error::PSNServerError;
+use crate::model::{unix_timestamp, PSNAccountInfo, PSNInnerInfo, PSNPoolReport, RefresherStatus};
+
+/// The accounts loaded into PSN pool.
+///
+/// PSN pool can only take a whole new set of inners so this list is the source of truth and the
+/// pool is rebuilt from it every time accounts are added or removed.
+///
+/// When a credential store is given the accounts are written to it on every change.
+#[derive(Clone)]
+pub struct SharedAccounts {
+    state: Arc<Mutex<Accounts>>,
+    store: Option<Arc<CredentialStore>>,
+}
+
+struct Accounts {
+    accounts: Vec<Account>,
+    // id of the next account added.
+    next_id: u64,
+    // pool is paused by admin. It stays paused when accounts change.
+    paused: bool,
+    refresher: RefresherStatus,
+}
+
+pub struct Account {
+    // an account removed and added again with the same email gets a new id.
+    id: u64,
+    pub inner: PSNInner,
+    pub added_at: u64,
+    // unhealthy accounts are kept in the list but taken out of pool.
+    pub healthy: bool,
+    pub token_expires_at: u64,
+    pub last_refresh_at: Option<u64>,
+    pub last_refresh_ok: Option<bool>,
+    pub last_error: Option<String>,
+}
+
+impl Account {
+    // inner must have its access token generated.
+    fn new(id: u64, inner: PSNInner) -> Self {
+        let now = unix_timestamp();
+        Self {
+            id,
+            inner,
+            added_at: now,
+            healthy: true,
+            token_expires_at: now + ACCESS_TOKEN_TTL,
+            last_refresh_at: None,
+            last_refresh_ok: None,
+            last_error: None,
+        }
+    }
+
+    fn info(&self) -> PSNAccountInfo {
+        PSNAccountInfo {
+            email: self.inner.get_email().to_owned(),
+            online_id: self.inner.get_self_online_id().to_owned(),
+            region: self.inner.get_region().to_owned(),
+            language: self.inner.get_lang().to_owned(),
+            added_at: self.added_at,
+            healthy: self.healthy,
+            token_expires_at: self.token_expires_at,
+            last_refresh_at: self.last_refresh_at,
+            last_refresh_ok: self.last_refresh_ok,
+            last_error: self.last_error.clone(),
+        }
+    }
+}
+
+impl SharedAccounts {
+    pub fn new(store: Option<CredentialStore>) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(Accounts {
+                accounts: Vec::new(),
+                next_id: 0,
+                paused: false,
+                refresher: RefresherStatus::default(),
+            })),
+            store: store.map(Arc::new),
+        }
+    }
+
+    /// Restore accounts from credential store.
+    ///
+    /// Restored accounts have no access token yet. They are kept as unhealthy and out of pool until
+    /// the refresher brings them back.
+    pub fn restore(&self, psn: &PSN) -> Result<usize, PSNServerError> {
+        let stored = match self.store.as_ref() {
+            Some(store) => store.load()?,
+            None => return Ok(0),
+        };
+
+        let len = stored.len();
+
+        let mut state = self.state.lock().unwrap();
+        state.accounts = stored
+            .into_iter()
+            .map(|s| {
+                let mut inner = PSNInner::new();
+                inner
+                    .set_email(s.email)
+                    .set_self_online_id(s.online_id)
+                    .set_region(s.region)
+                    .set_lang(s.language)
+                    .add_refresh_token(s.refresh_token);
+
+                let mut account = state.new_account(inner);
+                account.healthy = false;
+                account.token_expires_at = 0;
+                account
+            })
+            .collect();
+        state.rebuild(psn);
+
+        Ok(len)
+    }
+
+    fn persist(&self, state: &Accounts) {
+        let store = match self.store.as_ref() {
+            Some(store) => store,
+            None => return,
+        };
+
+        let accounts = state
+            .accounts
+            .iter()
+            .filter_map(|a| {
+                Some(StoredAccount {
+                    email: a.inner.get_email().to_owned(),
+                    online_id: a.inner.get_self_online_id().to_owned(),
+                    region: a.inner.get_region().to_owned(),
+                    language: a.inner.get_lang().to_owned(),
+                    refresh_token: a.inner.get_refresh_token()?.to_owned(),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        if let Err(e) = store.save(&accounts) {
+            error!("{}", e);
+        }
+    }
+
+    /// Add accounts to the running pool. Accounts with an email already in pool are replaced.
+    pub fn add(&self, psn: &PSN, inners: Vec<PSNInner>) {
+        let mut state = self.state.lock().unwrap();
+
+        for inner in inners.into_iter() {
+            state
+                .accounts
+                .retain(|a| a.inner.get_email() != inner.get_email());
+            let account = state.new_account(inner);
+            state.accounts.push(account);
+        }
+
+        state.rebuild(psn);
+        self.persist(&state);
+    }
+
+    /// Replace all accounts in pool.
+    pub fn replace(&self, psn: &PSN, inners: Vec<PSNInner>) {
+        let mut state = self.state.lock().unwrap();
+        state.accounts = inners
+            .into_iter()
+            .map(|inner| state.new_account(inner))
+            .collect();
+
+        state.rebuild(psn);
+        self.persist(&state);
+    }
+
+    /// Remove the account with given email. Return false if no such account.
+    pub fn remove(&self, psn: &PSN, email: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        let len = state.accounts.len();
+        state.accounts.retain(|a| a.inner.get_email() != email);
+
+        if state.accounts.len() == len {
+            return false;
+        }
+
+        state.rebuild(psn);
+        self.persist(&state);
+        true
+    }
+
+    pub fn pause(&self, psn: &PSN) {
+        self.state.lock().unwrap().paused = true;
+        psn.pause_inner();
+    }
+
+    pub fn resume(&self, psn: &PSN) {
+        let mut state = self.state.lock().unwrap();
+        state.paused = false;
+        state.rebuild(psn);
+    }
+
+    /// Refresh every account with access token expiring within `refresh_ahead`.
+    ///
+    /// Unhealthy accounts are tried on every run and put back to pool once they refresh again.
+    /// The pool is rebuilt with new tokens and without accounts failed all retries.
+    pub async fn refresh_due(&self, psn: &PSN, config: &RefresherConfig) {
+        let due = {
+            let state = self.state.lock().unwrap();
+            let deadline = unix_timestamp() + config.refresh_ahead;
+            state
+                .accounts
+                .iter()
+                .filter(|a| !a.healthy || a.token_expires_at <= deadline)
+                .map(|a| (a.id, a.inner.clone()))
+                .collect::<Vec<_>>()
+        };
+
+        // lock can't be held across await. Results are applied after all refreshes are done.
+        let mut results = Vec::with_capacity(due.len());
+        for (id, mut inner) in due.into_iter() {
+            let res = refresh_with_retry(&mut inner, config).await;
+            results.push((id, inner, res));
+        }
+
+        let now = unix_timestamp();
+        let mut state = self.state.lock().unwrap();
+        state.refresher.last_run_at = Some(now);
+
+        if results.is_empty() {
+            return;
+        }
+
+        let mut last_error = None;
+        let (mut successes, mut failures) = (0, 0);
+        for (id, inner, res) in results.into_iter() {
+            // account could be removed or replaced while refreshing.
+            let account = match state.accounts.iter_mut().find(|a| a.id == id) {
+                Some(account) => account,
+                None => continue,
+            };
+
+            account.last_refresh_at = Some(now);
+            account.last_refresh_ok = Some(res.is_ok());
+            match res {
+                Ok(_) => {
+                    successes += 1;
+                    account.inner = inner;
+                    account.token_expires_at = now + ACCESS_TOKEN_TTL;
+                    account.healthy = true;
+                }
+                Err(e) => {
+                    failures += 1;
+                    account.healthy = false;
+                    account.last_error = Some(e.clone());
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        state.refresher.successes += successes;
+        state.refresher.failures += failures;
+
+        match last_error {
+            Some(e) => state.refresher.last_error = Some(e),
+            None => state.refresher.last_success_at = Some(now),
+        }
+
+        state.rebuild(psn);
+        self.persist(&state);
+    }
+
+    pub fn list(&self) -> Vec<PSNAccountInfo> {
+        let state = self.state.lock().unwrap();
+        state.accounts.iter().map(Account::info).collect()
+    }
+
+    /// Access token of the healthy account with given email.
+    ///
+    /// Email can only be left out when the pool has one healthy account. Message threads belong to
+    /// one account so any other account would answer for a different inbox.
+    pub fn access_token(&self, email: Option<&str>) -> Result<String, PSNServerError> {
+        let state = self.state.lock().unwrap();
+        let mut healthy = state.accounts.iter().filter(|a| a.healthy);
+
+        let account = match email {
+            Some(email) => healthy.find(|a| a.inner.get_email() == email),
+            None => {
+                let first = healthy.next();
+                if healthy.next().is_some() {
+                    return Err(PSNServerError::BadRequest(
+                        "email is required when the pool has more than one account".into(),
+                    ));
+                }
+                first
+            }
+        };
+
+        account
+            .and_then(|a| a.inner.get_access_token())
+            .map(String::from)
+            .ok_or_else(|| PSNServerError::NotFound("healthy PSN account".into()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().accounts.len()
+    }
+
+    pub fn healthy_count(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.accounts.iter().filter(|a| a.healthy).count()
+    }
+
+    pub fn report(&self) -> PSNPoolReport {
+        let state = self.state.lock().unwrap();
+        let healthy = state.accounts.iter().filter(|a| a.healthy).count();
+
+        PSNPoolReport {
+            status: 200,
+            pool_size: healthy,
+            total_accounts: state.accounts.len(),
+            paused: state.paused || healthy == 0,
+            paused_by_admin: state.paused,
+            refresher: state.refresher.clone(),
+            accounts: state.accounts.iter().map(Account::info).collect(),
+        }
+    }
+}
+
+impl Accounts {
+    fn new_account(&mut self, inner: PSNInner) -> Account {
+        self.next_id += 1;
+        Account::new(self.next_id, inner)
+    }
+
+    // only healthy accounts go into pool. The pool is paused when there is none.
+    fn rebuild(&self, psn: &PSN) {
+        let inners = self
+            .accounts
+            .iter()
+            .filter(|a| a.healthy)
+            .map(|a| a.inner.clone())
+            .collect::<Vec<_>>();
+
+        psn.pause_inner();
+
+        if inners.is_empty() {
+            psn.clear_inner();
+            return;
+        }
+
+        psn.set_psn_inner_max(inners.len());
+        psn.add_psn_inner(inners);
+        psn.clear_inner();
+
+        if !self.paused {
+            psn.resume_inner();
+        }
+    }
+}
+
+async fn refresh_with_retry(inner: &mut PSNInner, config: &RefresherConfig) -> Result<(), String> {
+    let mut retries = 0;
+    let mut backoff = config.retry_backoff();
+
+    loop {
+        let res = match PSN::new_client() {
+            Ok(client) => inner.gen_access_from_refresh(&client).await.map(|_| ()),
+            Err(e) => Err(e),
+        };
+
+        match res {
+            Ok(_) => return Ok(()),
+            Err(e) => {
+                if retries == config.max_retries {
+                    return Err(e.to_string());
+                }
+                retries += 1;
+                ntex_rt::time::delay_for(backoff).await;
+                backoff *= 2;
+            }
+        }
+    }
+}
+
+/// Build a `PSNInner` from npsso code and generate its access and refresh token.
+pub(crate) async fn build_inner(info: PSNInnerInfo) -> Result<PSNInner, PSNServerError> {
+    let online_id = info.online_id.unwrap_or_else(|| String::from(""));
+    let region = info.region.unwrap_or_else(|| String::from("hk"));
+    let lang = info.language.unwrap_or_else(|| String::from("en"));
+
+    let mut inner = PSNInner::new();
+    inner
+        .set_email(info.email)
+        .set_self_online_id(online_id)
+        .set_region(region)
+        .set_lang(lang)
+        .add_npsso(info.npsso);
+
+    let client = PSN::new_client()?;
+    inner.gen_access_and_refresh(&client).await?;
+
+    Ok(inner)
+}
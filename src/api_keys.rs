@@ -0,0 +1,368 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use derive_more::Display;
+use openssl::memcmp;
+use openssl::rand::rand_bytes;
+use openssl::sha::sha256;
+
+use crate::config::{ApiKeysConfig, MessageAuthMode, MessageConfig};
+use crate::error::PSNServerError;
+use crate::model::unix_timestamp;
+
+// prefix of every generated key so they are easy to spot in logs and configs.
+const KEY_PREFIX: &str = "psk_";
+
+#[derive(Clone, Copy, Debug, Deserialize, Display, Serialize, PartialEq)]
+pub enum Scope {
+    #[serde(rename = "read:profile")]
+    #[display(fmt = "read:profile")]
+    ReadProfile,
+    #[serde(rename = "read:trophies")]
+    #[display(fmt = "read:trophies")]
+    ReadTrophies,
+    #[serde(rename = "send:message")]
+    #[display(fmt = "send:message")]
+    SendMessage,
+    #[serde(rename = "admin:pool")]
+    #[display(fmt = "admin:pool")]
+    AdminPool,
+    #[serde(rename = "admin:solver")]
+    #[display(fmt = "admin:solver")]
+    AdminSolver,
+}
+
+/// An api key. Only the sha256 hash of the key is kept.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+    pub revoked_at: Option<u64>,
+    hash: String,
+}
+
+impl ApiKey {
+    pub fn is_active(&self, now: u64) -> bool {
+        self.revoked_at.is_none() && self.expires_at.map(|at| at > now).unwrap_or(true)
+    }
+
+    pub fn require(&self, scope: Scope) -> Result<(), PSNServerError> {
+        if self.scopes.contains(&scope) {
+            Ok(())
+        } else {
+            Err(PSNServerError::Forbidden(format!(
+                "Api key is missing scope {}",
+                scope
+            )))
+        }
+    }
+
+    pub fn info(&self) -> ApiKeyInfo<'_> {
+        ApiKeyInfo {
+            id: &self.id,
+            name: &self.name,
+            scopes: &self.scopes,
+            created_at: self.created_at,
+            expires_at: self.expires_at,
+            revoked_at: self.revoked_at,
+            active: self.is_active(unix_timestamp()),
+        }
+    }
+}
+
+/// What admin can see of a key. Never the hash.
+#[derive(Serialize)]
+pub struct ApiKeyInfo<'a> {
+    pub id: &'a str,
+    pub name: &'a str,
+    pub scopes: &'a [Scope],
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+    pub revoked_at: Option<u64>,
+    pub active: bool,
+}
+
+/// Api keys created at runtime by admin.
+///
+/// Keys are written to a json file on every change when a path is configured. The admin token is
+/// not one of them and always has every scope.
+///
+/// Deprecated `message.keys` are taken as api keys with only send:message scope. They come from
+/// config so they are never written, listed or revoked.
+#[derive(Clone)]
+pub struct SharedApiKeys(Arc<ApiKeysInner>);
+
+struct ApiKeysInner {
+    path: Option<PathBuf>,
+    keys: Mutex<Vec<ApiKey>>,
+    message_keys: Vec<ApiKey>,
+}
+
+impl SharedApiKeys {
+    pub fn new(config: &ApiKeysConfig, message: &MessageConfig) -> Result<Self, PSNServerError> {
+        let path = config.path.as_ref().map(PathBuf::from);
+
+        let keys = match path.as_ref() {
+            Some(path) if path.exists() => {
+                let content = fs::read(path).map_err(|e| {
+                    PSNServerError::General500(format!("Failed to read api keys: {}", e))
+                })?;
+                serde_json::from_slice(&content).map_err(|e| {
+                    PSNServerError::General500(format!("Failed to parse api keys: {}", e))
+                })?
+            }
+            _ => Vec::new(),
+        };
+
+        let message_keys = match message.auth {
+            MessageAuthMode::Key => message.keys.iter().map(|k| message_key(k)).collect(),
+            MessageAuthMode::Admin => Vec::new(),
+        };
+
+        Ok(Self(Arc::new(ApiKeysInner {
+            path,
+            keys: Mutex::new(keys),
+            message_keys,
+        })))
+    }
+
+    /// Create a key and return it along with the plain key. The plain key can't be seen again.
+    pub fn create(
+        &self,
+        name: String,
+        scopes: Vec<Scope>,
+        expires_in: Option<u64>,
+    ) -> Result<(ApiKey, String), PSNServerError> {
+        let mut buf = [0u8; 32];
+        rand_bytes(&mut buf)
+            .map_err(|e| PSNServerError::General500(format!("Failed to generate key: {}", e)))?;
+        let plain = format!("{}{}", KEY_PREFIX, to_hex(&buf));
+
+        let now = unix_timestamp();
+        let key = ApiKey {
+            id: uuid::Uuid::new_v4().to_string(),
+            name,
+            scopes,
+            created_at: now,
+            expires_at: expires_in.map(|secs| now + secs),
+            revoked_at: None,
+            hash: hash_key(&plain),
+        };
+
+        let mut keys = self.0.keys.lock().unwrap();
+        keys.push(key.clone());
+        self.flush(&keys)?;
+
+        Ok((key, plain))
+    }
+
+    pub fn list(&self) -> Vec<ApiKey> {
+        self.0.keys.lock().unwrap().clone()
+    }
+
+    /// Revoke the key with given id. Return false if no such key.
+    pub fn revoke(&self, id: &str) -> Result<bool, PSNServerError> {
+        let mut keys = self.0.keys.lock().unwrap();
+
+        match keys.iter_mut().find(|k| k.id == id) {
+            Some(key) => {
+                if key.revoked_at.is_none() {
+                    key.revoked_at = Some(unix_timestamp());
+                }
+            }
+            None => return Ok(false),
+        }
+
+        self.flush(&keys).map(|_| true)
+    }
+
+    /// Find the active key matching a plain key.
+    pub fn verify(&self, plain: &str) -> Option<ApiKey> {
+        let hash = hash_key(plain);
+        let now = unix_timestamp();
+
+        self.0
+            .keys
+            .lock()
+            .unwrap()
+            .iter()
+            .chain(self.0.message_keys.iter())
+            .find(|k| memcmp::eq(k.hash.as_bytes(), hash.as_bytes()))
+            .filter(|k| k.is_active(now))
+            .cloned()
+    }
+
+    fn flush(&self, keys: &[ApiKey]) -> Result<(), PSNServerError> {
+        let path = match self.0.path.as_ref() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let content = serde_json::to_vec(keys)
+            .map_err(|e| PSNServerError::General500(format!("Failed to encode api keys: {}", e)))?;
+
+        let tmp = path.with_extension("tmp");
+
+        fs::write(&tmp, content)
+            .and_then(|_| fs::rename(&tmp, path))
+            .map_err(|e| PSNServerError::General500(format!("Failed to write api keys: {}", e)))
+    }
+}
+
+// the id is a short prefix of the hash so the key is recognizable in audit log and throttles.
+fn message_key(plain: &str) -> ApiKey {
+    let hash = hash_key(plain);
+
+    ApiKey {
+        id: format!("message_key_{}", &hash[..16]),
+        name: String::from("message.keys"),
+        scopes: vec![Scope::SendMessage],
+        created_at: unix_timestamp(),
+        expires_at: None,
+        revoked_at: None,
+        hash,
+    }
+}
+
+fn hash_key(plain: &str) -> String {
+    to_hex(&sha256(plain.as_bytes()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::Caller;
+
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new() -> Self {
+            Self(std::env::temp_dir().join(format!("{}.json", uuid::Uuid::new_v4())))
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    // kept in memory only.
+    fn config() -> ApiKeysConfig {
+        ApiKeysConfig {
+            path: None,
+            ..ApiKeysConfig::default()
+        }
+    }
+
+    fn keys() -> SharedApiKeys {
+        SharedApiKeys::new(&config(), &MessageConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn create_and_verify() {
+        let keys = keys();
+
+        let (key, plain) = keys
+            .create("reader".into(), vec![Scope::ReadProfile], None)
+            .unwrap();
+        assert!(plain.starts_with(KEY_PREFIX));
+
+        assert_eq!(keys.verify(&plain).unwrap().id, key.id);
+        assert!(keys.verify("psk_wrong").is_none());
+        assert!(keys.verify(&plain[1..]).is_none());
+        assert_eq!(keys.list().len(), 1);
+    }
+
+    #[test]
+    fn revoke() {
+        let keys = keys();
+        let (key, plain) = keys.create("reader".into(), Vec::new(), None).unwrap();
+
+        assert!(keys.revoke(&key.id).unwrap());
+        assert!(keys.verify(&plain).is_none());
+        assert!(keys.list()[0].revoked_at.is_some());
+
+        assert!(!keys.revoke("no_such_key").unwrap());
+    }
+
+    #[test]
+    fn expiry() {
+        let keys = keys();
+
+        let (_, expired) = keys.create("expired".into(), Vec::new(), Some(0)).unwrap();
+        let (key, plain) = keys.create("active".into(), Vec::new(), Some(60)).unwrap();
+
+        assert!(keys.verify(&expired).is_none());
+        assert!(keys.verify(&plain).is_some());
+        assert!(!key.is_active(unix_timestamp() + 60));
+    }
+
+    #[test]
+    fn persisted() {
+        let path = TempPath::new();
+        let config = ApiKeysConfig {
+            path: Some(path.0.to_string_lossy().into_owned()),
+            ..ApiKeysConfig::default()
+        };
+
+        let (key, plain) = SharedApiKeys::new(&config, &MessageConfig::default())
+            .unwrap()
+            .create("reader".into(), Vec::new(), None)
+            .unwrap();
+
+        // only the hash is written.
+        assert!(!fs::read_to_string(&path.0).unwrap().contains(&plain));
+
+        let keys = SharedApiKeys::new(&config, &MessageConfig::default()).unwrap();
+        assert_eq!(keys.verify(&plain).unwrap().id, key.id);
+    }
+
+    #[test]
+    fn message_keys() {
+        let message = MessageConfig {
+            auth: MessageAuthMode::Key,
+            keys: vec![String::from("message_key")],
+            ..MessageConfig::default()
+        };
+        let keys = SharedApiKeys::new(&config(), &message).unwrap();
+
+        let key = keys.verify("message_key").unwrap();
+        assert_eq!(key.name, "message.keys");
+        assert!(key.require(Scope::SendMessage).is_ok());
+        assert!(key.require(Scope::ReadProfile).is_err());
+
+        // config keys are never listed.
+        assert!(keys.list().is_empty());
+    }
+
+    #[test]
+    fn caller_scopes() {
+        let keys = keys();
+        let (key, _) = keys
+            .create("reader".into(), vec![Scope::ReadProfile], None)
+            .unwrap();
+
+        assert!(Caller::Admin.require(Scope::AdminPool).is_ok());
+
+        let caller = Caller::Key(key);
+        assert!(caller.require(Scope::ReadProfile).is_ok());
+        match caller.require(Scope::SendMessage) {
+            Err(PSNServerError::Forbidden(_)) => {}
+            _ => panic!("key without scope must be forbidden"),
+        }
+
+        match Caller::Anonymous.require(Scope::ReadProfile) {
+            Err(PSNServerError::Authorization) => {}
+            _ => panic!("anonymous caller must be unauthorized"),
+        }
+    }
+}
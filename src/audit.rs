@@ -0,0 +1,319 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::error;
+use ntex::http::StatusCode;
+use ntex::web::{HttpRequest, HttpResponse};
+use serde_json::Value;
+
+use crate::config::AuditConfig;
+use crate::error::PSNServerError;
+use crate::extractor::caller_identity;
+use crate::model::{unix_timestamp, AuditQuery};
+
+// values of these fields never reach the log, no matter how deep they are.
+const REDACTED_FIELDS: &[&str] = &[
+    "password",
+    "npsso",
+    "token",
+    "admin_token",
+    "api_key",
+    "refresh_token",
+    "image",
+];
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct AuditEntry {
+    pub at: u64,
+    // "admin", "api_key:<id>", "unauthenticated" or "anonymous".
+    pub actor: String,
+    pub ip: String,
+    // name of the operation. Requests denied before reaching it are logged as "<method> <path>".
+    pub action: String,
+    pub detail: Value,
+    // http status answered to the caller.
+    pub status: u16,
+    pub error: Option<String>,
+}
+
+// what a handler knows about the operation. The entry is written when the response is ready.
+struct AuditNote {
+    actor: String,
+    action: &'static str,
+    detail: Value,
+    error: Option<String>,
+}
+
+/// Append only audit log in json lines.
+///
+/// Entries are written by a dedicated thread so requests never wait on the disk. The file is
+/// opened in append mode and can be rotated with logrotate `copytruncate`. Recent entries are also
+/// kept in memory for `GET /admin/audit`. They are loaded from the file on start up.
+#[derive(Clone)]
+pub struct SharedAuditLog(Arc<AuditInner>);
+
+struct AuditInner {
+    writer: Option<Mutex<Sender<Vec<u8>>>>,
+    recent: Mutex<VecDeque<AuditEntry>>,
+    capacity: usize,
+}
+
+impl SharedAuditLog {
+    pub fn new(config: &AuditConfig) -> Result<Self, PSNServerError> {
+        let capacity = config.recent_entries;
+        let mut recent = VecDeque::with_capacity(capacity);
+
+        let writer = match config.path.as_ref() {
+            Some(path) => {
+                if let Ok(content) = fs::read_to_string(path) {
+                    // a broken line should not stop the service from starting.
+                    for entry in content
+                        .lines()
+                        .filter_map(|line| serde_json::from_str::<AuditEntry>(line).ok())
+                    {
+                        if recent.len() == capacity {
+                            recent.pop_front();
+                        }
+                        recent.push_back(entry);
+                    }
+                }
+
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| {
+                        PSNServerError::General500(format!("Failed to open audit log: {}", e))
+                    })?;
+                Some(Mutex::new(spawn_writer(file)))
+            }
+            None => None,
+        };
+
+        Ok(Self(Arc::new(AuditInner {
+            writer,
+            recent: Mutex::new(recent),
+            capacity,
+        })))
+    }
+
+    /// Describe the operation of a request. `detail` is redacted before it's written.
+    ///
+    /// Nothing is written here. `record_response` writes the entry with the status actually
+    /// answered, and writes one for requests denied before reaching a handler too.
+    pub fn annotate(
+        req: &HttpRequest,
+        actor: &str,
+        action: &'static str,
+        detail: Value,
+        res: &Result<HttpResponse, PSNServerError>,
+    ) {
+        req.extensions_mut().insert(AuditNote {
+            actor: actor.to_owned(),
+            action,
+            detail,
+            error: res.as_ref().err().map(|e| format!("{}", e)),
+        });
+    }
+
+    /// Write the entry of a finished request. Used by the middleware around audited routes.
+    pub fn record_response(req: &HttpRequest, status: StatusCode) {
+        let audit = match req.app_data::<SharedAuditLog>() {
+            Some(audit) => audit,
+            None => return,
+        };
+
+        let note = req.extensions_mut().remove::<AuditNote>();
+        let (actor, action, mut detail, error) = match note {
+            Some(note) => (note.actor, note.action.to_owned(), note.detail, note.error),
+            None => (
+                caller_identity(req),
+                format!("{} {}", req.method(), req.path()),
+                Value::Null,
+                None,
+            ),
+        };
+
+        redact(&mut detail);
+
+        audit.push(AuditEntry {
+            at: unix_timestamp(),
+            actor,
+            ip: req
+                .peer_addr()
+                .map(|addr| addr.ip().to_string())
+                .unwrap_or_default(),
+            action,
+            detail,
+            status: status.as_u16(),
+            error,
+        });
+    }
+
+    fn push(&self, entry: AuditEntry) {
+        if let Some(writer) = self.0.writer.as_ref() {
+            match serde_json::to_vec(&entry) {
+                Ok(mut line) => {
+                    line.push(b'\n');
+                    if writer.lock().unwrap().send(line).is_err() {
+                        error!("Audit log writer is gone");
+                    }
+                }
+                Err(e) => error!("Failed to encode audit entry: {}", e),
+            }
+        }
+
+        let mut recent = self.0.recent.lock().unwrap();
+        if recent.len() == self.0.capacity {
+            recent.pop_front();
+        }
+        recent.push_back(entry);
+    }
+
+    /// Recent entries matching the query. Newest first.
+    pub fn query(&self, query: &AuditQuery) -> Vec<AuditEntry> {
+        self.0
+            .recent
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|e| {
+                query
+                    .action
+                    .as_ref()
+                    .map(|a| &e.action == a)
+                    .unwrap_or(true)
+            })
+            .filter(|e| query.actor.as_ref().map(|a| &e.actor == a).unwrap_or(true))
+            .filter(|e| query.since.map(|since| e.at >= since).unwrap_or(true))
+            .take(query.limit)
+            .cloned()
+            .collect()
+    }
+}
+
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if REDACTED_FIELDS.contains(&key.as_str()) && !value.is_null() {
+                    *value = Value::String("[REDACTED]".into());
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+// lines queued while writing are written together before the buffer is flushed.
+fn spawn_writer(file: File) -> Sender<Vec<u8>> {
+    let (tx, rx) = mpsc::channel::<Vec<u8>>();
+
+    thread::spawn(move || {
+        let mut writer = BufWriter::new(file);
+
+        // lifecycle: the thread ends when the audit log is dropped on exit.
+        while let Ok(line) = rx.recv() {
+            let mut res = writer.write_all(&line);
+            while let Ok(line) = rx.try_recv() {
+                res = res.and_then(|_| writer.write_all(&line));
+            }
+
+            if let Err(e) = res.and_then(|_| writer.flush()) {
+                error!("Failed to write audit log: {}", e);
+            }
+        }
+    });
+
+    tx
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn entry(at: u64, actor: &str, action: &str) -> AuditEntry {
+        AuditEntry {
+            at,
+            actor: actor.into(),
+            ip: String::from("127.0.0.1"),
+            action: action.into(),
+            detail: json!({}),
+            status: 200,
+            error: None,
+        }
+    }
+
+    fn query(action: Option<&str>, actor: Option<&str>, since: Option<u64>) -> AuditQuery {
+        AuditQuery {
+            action: action.map(String::from),
+            actor: actor.map(String::from),
+            since,
+            limit: 100,
+        }
+    }
+
+    #[test]
+    fn redact_nested() {
+        let mut detail = json!({
+            "email": "a@b.c",
+            "password": "secret",
+            "accounts": [
+                { "email": "a@b.c", "npsso": "npsso_code" },
+                { "email": "d@e.f", "npsso": null },
+            ],
+            "token": { "value": "admin_token" },
+        });
+
+        redact(&mut detail);
+
+        assert_eq!(
+            detail,
+            json!({
+                "email": "a@b.c",
+                "password": "[REDACTED]",
+                "accounts": [
+                    { "email": "a@b.c", "npsso": "[REDACTED]" },
+                    { "email": "d@e.f", "npsso": null },
+                ],
+                "token": "[REDACTED]",
+            })
+        );
+    }
+
+    #[test]
+    fn recent_entries() {
+        let log = SharedAuditLog::new(&AuditConfig {
+            path: None,
+            recent_entries: 3,
+        })
+        .unwrap();
+
+        log.push(entry(1, "admin", "set_npsso"));
+        log.push(entry(2, "api_key:id", "send_message"));
+        log.push(entry(3, "admin", "send_message"));
+        log.push(entry(4, "admin", "remove_account"));
+
+        // the oldest entry is dropped and the newest comes first.
+        let at = |entries: Vec<AuditEntry>| entries.iter().map(|e| e.at).collect::<Vec<_>>();
+        assert_eq!(at(log.query(&query(None, None, None))), vec![4, 3, 2]);
+        assert_eq!(
+            at(log.query(&query(Some("send_message"), None, None))),
+            vec![3, 2]
+        );
+        assert_eq!(
+            at(log.query(&query(Some("send_message"), Some("admin"), None))),
+            vec![3]
+        );
+        assert_eq!(at(log.query(&query(None, None, Some(3)))), vec![4, 3]);
+    }
+}
@@ -0,0 +1,323 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use openssl::sha::sha1;
+
+use crate::config::CacheConfig;
+use crate::model::PSNQuery;
+
+/// Cache of serialized PSN query responses shared by all workers.
+///
+/// Entries expire by the ttl of their query type. When the cache is full the least recently used
+/// entry is evicted.
+#[derive(Clone)]
+pub struct SharedCache(Arc<CacheInner>);
+
+struct CacheInner {
+    config: CacheConfig,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Default)]
+struct Entries {
+    slots: HashMap<String, CacheSlot>,
+    // keys by the tick of their last access. The first one is the least recently used.
+    order: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+struct CacheSlot {
+    entry: Arc<CacheEntry>,
+    last_access: u64,
+}
+
+impl Entries {
+    // move the entry to the back of the lru order.
+    fn touch(&mut self, key: &str) {
+        self.tick += 1;
+        let tick = self.tick;
+
+        if let Some(slot) = self.slots.get_mut(key) {
+            if let Some(key) = self.order.remove(&slot.last_access) {
+                self.order.insert(tick, key);
+            }
+            slot.last_access = tick;
+        }
+    }
+
+    fn insert(&mut self, key: String, entry: Arc<CacheEntry>) {
+        self.remove(&key);
+
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.slots.insert(
+            key,
+            CacheSlot {
+                entry,
+                last_access: self.tick,
+            },
+        );
+    }
+
+    fn remove(&mut self, key: &str) -> Option<CacheSlot> {
+        let slot = self.slots.remove(key)?;
+        self.order.remove(&slot.last_access);
+        Some(slot)
+    }
+
+    fn remove_lru(&mut self) {
+        let key = self.order.values().next().cloned();
+        if let Some(key) = key {
+            self.remove(&key);
+        }
+    }
+}
+
+pub struct CacheEntry {
+    pub body: Bytes,
+    // quoted as it goes to ETag header.
+    pub etag: String,
+    pub online_id: Option<String>,
+    expires_at: Instant,
+}
+
+impl CacheEntry {
+    fn new(body: Bytes, online_id: Option<String>, ttl: Duration) -> Self {
+        let etag = sha1(&body)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+
+        Self {
+            body,
+            etag: format!("\"{}\"", etag),
+            online_id,
+            expires_at: Instant::now() + ttl,
+        }
+    }
+
+    /// Seconds left before the entry expires.
+    pub fn max_age(&self) -> u64 {
+        self.expires_at
+            .checked_duration_since(Instant::now())
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        now >= self.expires_at
+    }
+}
+
+impl SharedCache {
+    pub fn new(config: CacheConfig) -> Self {
+        Self(Arc::new(CacheInner {
+            config,
+            entries: Mutex::new(Entries::default()),
+        }))
+    }
+
+    pub fn get(&self, query: &PSNQuery) -> Option<Arc<CacheEntry>> {
+        if !self.0.config.enabled {
+            return None;
+        }
+
+        let now = Instant::now();
+        let mut entries = self.0.entries.lock().unwrap();
+        let key = query.cache_key();
+
+        let entry = entries.slots.get(&key).map(|slot| slot.entry.clone())?;
+
+        if entry.is_expired(now) {
+            entries.remove(&key);
+            None
+        } else {
+            entries.touch(&key);
+            Some(entry)
+        }
+    }
+
+    /// Store the response body of given query. The entry is returned even when caching is disabled
+    /// so it can be used to build the response.
+    pub fn insert(&self, query: &PSNQuery, body: Bytes) -> Arc<CacheEntry> {
+        let config = &self.0.config;
+        let ttl = if config.enabled {
+            Duration::from_secs(config.ttl_for(query))
+        } else {
+            Duration::from_secs(0)
+        };
+
+        let entry = Arc::new(CacheEntry::new(
+            body,
+            query.online_id().map(String::from),
+            ttl,
+        ));
+
+        if ttl.as_secs() == 0 {
+            return entry;
+        }
+
+        let key = query.cache_key();
+        let mut entries = self.0.entries.lock().unwrap();
+
+        // expired entries are left until they are read or become the least recently used.
+        if !entries.slots.contains_key(&key) && entries.slots.len() >= config.max_entries {
+            entries.remove_lru();
+        }
+
+        entries.insert(key, entry.clone());
+
+        entry
+    }
+
+    /// Remove all entries of given online_id in any case. Return the count of removed entries.
+    pub fn purge(&self, online_id: &str) -> usize {
+        let mut entries = self.0.entries.lock().unwrap();
+
+        let keys = entries
+            .slots
+            .iter()
+            .filter(|(_, slot)| {
+                slot.entry
+                    .online_id
+                    .as_deref()
+                    .map(|id| id.eq_ignore_ascii_case(online_id))
+                    .unwrap_or(false)
+            })
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+
+        for key in keys.iter() {
+            entries.remove(key);
+        }
+
+        keys.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cache(max_entries: usize) -> SharedCache {
+        SharedCache::new(CacheConfig {
+            max_entries,
+            ..CacheConfig::default()
+        })
+    }
+
+    fn profile(online_id: &str) -> PSNQuery {
+        PSNQuery::Profile {
+            online_id: online_id.into(),
+        }
+    }
+
+    fn titles(online_id: &str) -> PSNQuery {
+        PSNQuery::Titles {
+            online_id: online_id.into(),
+            offset: String::from("0"),
+        }
+    }
+
+    // replace the entry of query with one already expired.
+    fn expire(cache: &SharedCache, query: &PSNQuery) {
+        let mut entries = cache.0.entries.lock().unwrap();
+        let slot = entries.slots.get_mut(&query.cache_key()).unwrap();
+        slot.entry = Arc::new(CacheEntry::new(
+            slot.entry.body.clone(),
+            slot.entry.online_id.clone(),
+            Duration::from_secs(0),
+        ));
+    }
+
+    #[test]
+    fn ttl_expiry() {
+        let cache = cache(10);
+        let query = profile("player");
+
+        let entry = cache.insert(&query, Bytes::from_static(b"{}"));
+        assert!(entry.max_age() > 0);
+        assert_eq!(cache.get(&query).unwrap().etag, entry.etag);
+
+        expire(&cache, &query);
+        assert!(cache.get(&query).is_none());
+        assert!(cache.0.entries.lock().unwrap().slots.is_empty());
+    }
+
+    #[test]
+    fn disabled() {
+        let cache = SharedCache::new(CacheConfig {
+            enabled: false,
+            ..CacheConfig::default()
+        });
+        let query = profile("player");
+
+        assert_eq!(cache.insert(&query, Bytes::from_static(b"{}")).max_age(), 0);
+        assert!(cache.get(&query).is_none());
+    }
+
+    #[test]
+    fn lru_eviction() {
+        let cache = cache(2);
+
+        cache.insert(&profile("a"), Bytes::from_static(b"a"));
+        cache.insert(&profile("b"), Bytes::from_static(b"b"));
+
+        // a is read so b is the least recently used.
+        assert!(cache.get(&profile("a")).is_some());
+        cache.insert(&profile("c"), Bytes::from_static(b"c"));
+
+        assert!(cache.get(&profile("a")).is_some());
+        assert!(cache.get(&profile("b")).is_none());
+        assert!(cache.get(&profile("c")).is_some());
+
+        // replacing an entry evicts nothing.
+        cache.insert(&profile("c"), Bytes::from_static(b"c2"));
+        assert!(cache.get(&profile("a")).is_some());
+        assert_eq!(
+            cache.get(&profile("c")).unwrap().body,
+            Bytes::from_static(b"c2")
+        );
+
+        let entries = cache.0.entries.lock().unwrap();
+        assert_eq!(entries.slots.len(), 2);
+        assert_eq!(entries.order.len(), 2);
+    }
+
+    #[test]
+    fn purge() {
+        let cache = cache(10);
+
+        cache.insert(&profile("Player"), Bytes::from_static(b"{}"));
+        cache.insert(&titles("player"), Bytes::from_static(b"{}"));
+        cache.insert(&profile("other"), Bytes::from_static(b"{}"));
+
+        assert_eq!(cache.purge("PLAYER"), 2);
+        assert!(cache.get(&titles("player")).is_none());
+        assert!(cache.get(&profile("other")).is_some());
+        assert_eq!(cache.purge("player"), 0);
+
+        let entries = cache.0.entries.lock().unwrap();
+        assert_eq!(entries.order.len(), 1);
+    }
+
+    #[test]
+    fn cache_key() {
+        // online ids are matched in any case.
+        assert_eq!(profile("Player").cache_key(), profile("player").cache_key());
+        assert_ne!(profile("player").cache_key(), titles("player").cache_key());
+
+        // parts can't run into each other.
+        let a = PSNQuery::TrophySet {
+            online_id: String::from("a"),
+            np_communication_id: String::from("b,c"),
+        };
+        let b = PSNQuery::TrophySet {
+            online_id: String::from("a,b"),
+            np_communication_id: String::from("c"),
+        };
+        assert_ne!(a.cache_key(), b.cache_key());
+    }
+}
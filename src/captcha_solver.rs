@@ -7,8 +7,10 @@ use headless_chrome::protocol::network::methods::RequestPattern;
 use headless_chrome::{Browser, LaunchOptions};
 use reqwest::Client;
 
+use crate::config::SolverConfig;
 use crate::error::PSNServerError;
-use crate::model::{CaptchaResponse, PSNAccount, PSNNpssoResponse};
+use crate::metrics::SharedMetrics;
+use crate::model::{AccountStatus, CaptchaResponse, PSNAccount, PSNNpssoResponse};
 
 const USER_AGENT: &str = "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/78.0.3904.108 Safari/537.36";
 const URL: &str = "https://account.sonyentertainmentnetwork.com";
@@ -19,37 +21,49 @@ const TWO_CAP_RES_URL: &str = "https://2captcha.com/res.php";
 
 pub(crate) struct CaptchaSolver {
     browser: Browser,
-    api_key: String,
+    config: SolverConfig,
     client: Client,
+    metrics: SharedMetrics,
 }
 
 impl CaptchaSolver {
-    pub fn new(api_key: String) -> Self {
+    pub fn new(config: SolverConfig, metrics: SharedMetrics) -> Self {
         Self {
             browser: Browser::new(
                 LaunchOptions::default_builder()
-                    .headless(false)
+                    .headless(config.headless)
                     .window_size(Some((800, 600)))
                     .build()
                     .unwrap(),
             )
             .unwrap(),
-            api_key,
+            config,
             client: Client::new(),
+            metrics,
         }
     }
 
-    pub async fn get_npsso(&self, user: &PSNAccount) -> Result<PSNNpssoResponse, PSNServerError> {
+    /// `on_progress` is called every time the solver moves to the next step for this account.
+    pub async fn get_npsso(
+        &self,
+        user: &PSNAccount,
+        on_progress: &dyn Fn(AccountStatus),
+    ) -> Result<PSNNpssoResponse, PSNServerError> {
+        on_progress(AccountStatus::Navigating);
+
         let tab = self.browser.wait_for_initial_tab()?;
 
         tab.set_user_agent(USER_AGENT, None, None)?;
 
         tab.navigate_to(URL)?.wait_for_element_with_custom_timeout(
             "#g-recaptcha-response",
-            Duration::from_secs(15),
+            Duration::from_secs(self.config.navigation_timeout),
         )?;
 
-        tab.wait_for_element_with_custom_timeout("#ember19", Duration::from_secs(5))?;
+        tab.wait_for_element_with_custom_timeout(
+            "#ember19",
+            Duration::from_secs(self.config.element_timeout),
+        )?;
 
         tab.find_element("#ember19")?
             .focus()?
@@ -117,8 +131,14 @@ impl CaptchaSolver {
 
         let url = tab.get_url();
 
+        on_progress(AccountStatus::AwaitingCaptcha);
+
         let request_id = self.send(url).await?;
-        let captcha_answer = self.wait_receive(request_id).await?;
+        self.metrics.captcha_submitted();
+
+        let captcha_answer = self.wait_receive(request_id).await;
+        self.metrics.captcha_answered(captcha_answer.is_ok());
+        let captcha_answer = captcha_answer?;
 
         response_token
             .lock()
@@ -129,12 +149,15 @@ impl CaptchaSolver {
 
         tab.evaluate("widgetVerified(this)", false)?;
 
+        on_progress(AccountStatus::AwaitingNpsso);
+
         let mut retries = 0;
-        let mut interval = tokio::time::interval(Duration::from_secs(2));
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(self.config.npsso_poll_interval));
 
         loop {
             interval.tick().await;
-            if retries == 10 {
+            if retries == self.config.npsso_poll_retries {
                 return Err(PSNServerError::TimeOut);
             } else {
                 let mut n = npsso.lock().unwrap();
@@ -153,7 +176,7 @@ impl CaptchaSolver {
     async fn send(&self, url: String) -> Result<String, PSNServerError> {
         let mut hashmap = HashMap::new();
 
-        hashmap.insert("key", self.api_key.as_str());
+        hashmap.insert("key", self.config.api_key.as_str());
         hashmap.insert("method", "userrecaptcha");
         hashmap.insert("googlekey", SITE_KEY);
         hashmap.insert("invisible", "1");
@@ -199,15 +222,16 @@ impl CaptchaSolver {
     async fn wait_receive(&self, request_id: String) -> Result<String, PSNServerError> {
         let url = format!(
             "{}?key={}&action=get&id={}&json=1",
-            TWO_CAP_RES_URL, self.api_key, request_id
+            TWO_CAP_RES_URL, self.config.api_key, request_id
         );
 
-        tokio::time::delay_for(Duration::from_secs(25)).await;
+        tokio::time::delay_for(Duration::from_secs(self.config.captcha_initial_wait)).await;
 
         let mut retries = 0;
-        let mut interval = tokio::time::interval(Duration::from_secs(3));
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(self.config.captcha_poll_interval));
         loop {
-            if retries == 30 {
+            if retries == self.config.captcha_poll_retries {
                 return Err(PSNServerError::TimeOut);
             } else {
                 match self.try_receive(&url).await {
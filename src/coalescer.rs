@@ -0,0 +1,180 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::rc::Rc;
+
+use bytes::Bytes;
+use futures_util::future::{FutureExt, LocalBoxFuture, Shared};
+
+use crate::error::PSNServerError;
+
+type InFlight = Shared<LocalBoxFuture<'static, Result<Bytes, PSNServerError>>>;
+
+/// Deduplicate identical PSN queries in flight.
+///
+/// Concurrent queries with the same key share one upstream call and all get its result. PSN
+/// futures are not `Send` so every worker has its own coalescer.
+#[derive(Clone, Default)]
+pub struct Coalescer(Rc<RefCell<InFlights>>);
+
+#[derive(Default)]
+struct InFlights {
+    // every upstream call gets a new generation so a finished call never removes a newer one.
+    generation: u64,
+    queries: HashMap<String, (u64, InFlight)>,
+}
+
+impl Coalescer {
+    /// Run `fut` unless a query with the same key is already in flight, then wait for that one.
+    pub async fn run<F>(&self, key: String, fut: F) -> Result<Bytes, PSNServerError>
+    where
+        F: Future<Output = Result<Bytes, PSNServerError>> + 'static,
+    {
+        let (generation, in_flight) = {
+            let mut in_flights = self.0.borrow_mut();
+            let next = in_flights.generation + 1;
+
+            let (generation, in_flight) = in_flights
+                .queries
+                .entry(key.clone())
+                .or_insert_with(|| (next, fut.boxed_local().shared()))
+                .clone();
+
+            if generation == next {
+                in_flights.generation = next;
+            }
+
+            (generation, in_flight)
+        };
+
+        let res = in_flight.await;
+
+        // the first waiter woken up removes the entry unless a newer call already took its place.
+        let mut in_flights = self.0.borrow_mut();
+        if in_flights.queries.get(&key).map(|(g, _)| *g) == Some(generation) {
+            in_flights.queries.remove(&key);
+        }
+
+        res
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use futures_util::future::poll_fn;
+    use futures_util::task::noop_waker;
+
+    use super::*;
+
+    // an upstream call counted in `calls` that stays pending until `gate` is opened.
+    fn upstream(
+        calls: &Rc<Cell<usize>>,
+        gate: &Rc<Cell<bool>>,
+        body: &'static str,
+    ) -> impl Future<Output = Result<Bytes, PSNServerError>> + 'static {
+        let calls = calls.clone();
+        let gate = gate.clone();
+        let mut started = false;
+
+        poll_fn(move |_| {
+            if !started {
+                started = true;
+                calls.set(calls.get() + 1);
+            }
+            if gate.get() {
+                Poll::Ready(Ok(Bytes::from_static(body.as_bytes())))
+            } else {
+                Poll::Pending
+            }
+        })
+    }
+
+    fn poll<F: Future>(fut: &mut Pin<Box<F>>) -> Poll<F::Output> {
+        let waker = noop_waker();
+        fut.as_mut().poll(&mut Context::from_waker(&waker))
+    }
+
+    fn ready_body<F>(fut: &mut Pin<Box<F>>) -> Bytes
+    where
+        F: Future<Output = Result<Bytes, PSNServerError>>,
+    {
+        match poll(fut) {
+            Poll::Ready(res) => res.unwrap(),
+            Poll::Pending => panic!("expect the query to be finished"),
+        }
+    }
+
+    #[test]
+    fn same_key_shares_one_call() {
+        let coalescer = Coalescer::default();
+        let calls = Rc::new(Cell::new(0));
+        let gate = Rc::new(Cell::new(false));
+
+        let mut a = Box::pin(coalescer.run("key".into(), upstream(&calls, &gate, "a")));
+        let mut b = Box::pin(coalescer.run("key".into(), upstream(&calls, &gate, "b")));
+        assert!(poll(&mut a).is_pending());
+        assert!(poll(&mut b).is_pending());
+
+        gate.set(true);
+
+        assert_eq!(ready_body(&mut a), "a");
+        assert_eq!(ready_body(&mut b), "a");
+        assert_eq!(calls.get(), 1);
+        assert!(coalescer.0.borrow().queries.is_empty());
+    }
+
+    #[test]
+    fn different_keys_call_separately() {
+        let coalescer = Coalescer::default();
+        let calls = Rc::new(Cell::new(0));
+        let gate = Rc::new(Cell::new(false));
+
+        let mut a = Box::pin(coalescer.run("a".into(), upstream(&calls, &gate, "a")));
+        let mut b = Box::pin(coalescer.run("b".into(), upstream(&calls, &gate, "b")));
+        assert!(poll(&mut a).is_pending());
+        assert!(poll(&mut b).is_pending());
+
+        gate.set(true);
+
+        assert_eq!(ready_body(&mut a), "a");
+        assert_eq!(ready_body(&mut b), "b");
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn finished_call_keeps_newer_one() {
+        let coalescer = Coalescer::default();
+        let calls = Rc::new(Cell::new(0));
+        let first_gate = Rc::new(Cell::new(false));
+        let second_gate = Rc::new(Cell::new(false));
+
+        let mut a = Box::pin(coalescer.run("key".into(), upstream(&calls, &first_gate, "a")));
+        let mut b = Box::pin(coalescer.run("key".into(), upstream(&calls, &first_gate, "b")));
+        assert!(poll(&mut a).is_pending());
+        assert!(poll(&mut b).is_pending());
+
+        // a removes the finished call and c starts a new one before b wakes up.
+        first_gate.set(true);
+        assert_eq!(ready_body(&mut a), "a");
+
+        let mut c = Box::pin(coalescer.run("key".into(), upstream(&calls, &second_gate, "c")));
+        assert!(poll(&mut c).is_pending());
+
+        assert_eq!(ready_body(&mut b), "a");
+        assert_eq!(coalescer.0.borrow().queries.len(), 1);
+
+        // a query coming now joins c instead of calling again.
+        let mut d = Box::pin(coalescer.run("key".into(), upstream(&calls, &second_gate, "d")));
+        assert!(poll(&mut d).is_pending());
+
+        second_gate.set(true);
+        assert_eq!(ready_body(&mut c), "c");
+        assert_eq!(ready_body(&mut d), "c");
+        assert_eq!(calls.get(), 2);
+        assert!(coalescer.0.borrow().queries.is_empty());
+    }
+}
@@ -0,0 +1,997 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use derive_more::Display;
+
+use crate::model::PSNQuery;
+
+// config file is looked up in working dir when no path is given by --config or CONFIG_PATH.
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+// env vars kept from the .env old days. They override the values from config file.
+const ENV_OVERRIDES: &[(&str, &str)] = &[
+    ("ADDRESS", "server.address"),
+    ("PORT", "server.port"),
+    ("CORS_ORIGIN", "server.cors_origin"),
+    ("LEGACY_ERROR_STATUS", "server.legacy_error_status"),
+    ("BEARER_TOKEN", "auth.admin_token"),
+    ("KEY_PATH", "tls.key_path"),
+    ("CERT_PATH", "tls.cert_path"),
+    ("CAPTCHA_API_KEY", "solver.api_key"),
+    ("SOLVER_HEADLESS", "solver.headless"),
+    ("REFRESHER_INTERVAL", "refresher.interval"),
+    ("RATE_LIMITER_ENABLED", "rate_limiter.enabled"),
+    ("RATE_LIMITER_MAX_REQUESTS", "rate_limiter.max_requests"),
+    ("RATE_LIMITER_INTERVAL", "rate_limiter.interval"),
+    ("JOB_STORE_BACKEND", "job_store.backend"),
+    ("JOB_STORE_PATH", "job_store.path"),
+    ("JOB_STORE_TTL", "job_store.ttl"),
+    ("CACHE_ENABLED", "cache.enabled"),
+    ("CACHE_MAX_ENTRIES", "cache.max_entries"),
+    ("CREDENTIALS_PATH", "credentials.path"),
+    ("CREDENTIALS_KEY", "credentials.key"),
+    ("MESSAGE_AUTH", "message.auth"),
+    ("API_KEYS_PATH", "api_keys.path"),
+    ("AUDIT_PATH", "audit.path"),
+    ("METRICS_ENABLED", "metrics.enabled"),
+    ("HEALTH_CHECK_UPSTREAM", "health.check_upstream"),
+    (
+        "API_KEYS_REQUIRE_FOR_QUERIES",
+        "api_keys.require_for_queries",
+    ),
+];
+
+#[derive(Debug, Display)]
+pub enum ConfigError {
+    #[display(fmt = "Can not read config file {}: {}", _0, _1)]
+    Io(String, String),
+    #[display(fmt = "Can not parse config file {}: {}", _0, _1)]
+    Parse(String, String),
+    #[display(fmt = "Unknown config key: {}", _0)]
+    UnknownKey(String),
+    #[display(fmt = "Missing value for command line flag: --{}", _0)]
+    MissingValue(String),
+    #[display(fmt = "Invalid value {:?} for {}: {}", value, key, reason)]
+    Invalid {
+        key: String,
+        value: String,
+        reason: String,
+    },
+    #[display(fmt = "Invalid configuration:\n  - {}", "_0.join(\"\\n  - \")")]
+    Validation(Vec<String>),
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub tls: TlsConfig,
+    pub auth: AuthConfig,
+    pub solver: SolverConfig,
+    pub refresher: RefresherConfig,
+    pub rate_limiter: RateLimiterConfig,
+    pub job_store: JobStoreConfig,
+    pub credentials: CredentialsConfig,
+    pub cache: CacheConfig,
+    pub message: MessageConfig,
+    pub api_keys: ApiKeysConfig,
+    pub audit: AuditConfig,
+    pub metrics: MetricsConfig,
+    pub health: HealthConfig,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub address: String,
+    pub port: u16,
+    // if cors_origin is not provided than no CORS behavior is allowed.
+    // "All" allow all sites to make CORS sharing.
+    pub cors_origin: Option<String>,
+    // answer errors with http 200 and the status only in json body like older versions did.
+    pub legacy_error_status: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            address: String::from("0.0.0.0"),
+            port: 3000,
+            cors_origin: None,
+            legacy_error_status: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub key_path: Option<String>,
+    pub cert_path: Option<String>,
+}
+
+impl TlsConfig {
+    pub fn enabled(&self) -> bool {
+        self.key_path.is_some()
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    // requests with this bearer token in header would have access to admin API endpoints.
+    pub admin_token: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SolverConfig {
+    // two captcha service api key. It's used for generate npsso codes.
+    pub api_key: String,
+    pub headless: bool,
+    // all timeouts and intervals are in seconds.
+    pub navigation_timeout: u64,
+    pub element_timeout: u64,
+    pub captcha_initial_wait: u64,
+    pub captcha_poll_interval: u64,
+    pub captcha_poll_retries: u32,
+    pub npsso_poll_interval: u64,
+    pub npsso_poll_retries: u32,
+    // cost of one solved captcha. Only used to estimate spend in metrics.
+    pub captcha_price: f64,
+}
+
+impl Default for SolverConfig {
+    fn default() -> Self {
+        Self {
+            api_key: String::new(),
+            headless: false,
+            navigation_timeout: 15,
+            element_timeout: 5,
+            captcha_initial_wait: 25,
+            captcha_poll_interval: 3,
+            captcha_poll_retries: 30,
+            npsso_poll_interval: 2,
+            npsso_poll_retries: 10,
+            captcha_price: 0.00299,
+        }
+    }
+}
+
+// PSN access token lives for one hour after it's generated.
+pub const ACCESS_TOKEN_TTL: u64 = 3600;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RefresherConfig {
+    // how often accounts are checked for tokens about to expire. In seconds.
+    pub interval: u64,
+    // refresh access token when it expires within this many seconds.
+    pub refresh_ahead: u64,
+    // an account is taken out of pool after failing this many retries.
+    pub max_retries: u32,
+    // delay before the first retry. Doubled on every retry. In seconds.
+    pub retry_backoff: u64,
+}
+
+impl RefresherConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval)
+    }
+
+    pub fn retry_backoff(&self) -> Duration {
+        Duration::from_secs(self.retry_backoff)
+    }
+}
+
+impl Default for RefresherConfig {
+    fn default() -> Self {
+        Self {
+            interval: 60,
+            refresh_ahead: 600,
+            max_retries: 3,
+            retry_backoff: 5,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimiterConfig {
+    pub enabled: bool,
+    // default policy for routes without their own entry in routes.
+    pub max_requests: usize,
+    // in seconds.
+    pub interval: u64,
+    pub recycle_interval: u64,
+    pub routes: Vec<RouteLimitConfig>,
+    pub keys: Vec<KeyLimitConfig>,
+}
+
+impl Default for RateLimiterConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_requests: 60,
+            interval: 3600,
+            recycle_interval: 60,
+            routes: Vec::new(),
+            keys: Vec::new(),
+        }
+    }
+}
+
+/// Limit for one route. Matched against the exact request path.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteLimitConfig {
+    pub path: String,
+    pub max_requests: usize,
+    pub interval: u64,
+}
+
+/// Quota for api keys with the given name. Requests with `Authorization: Bearer <key>` are
+/// counted against the key instead of the client address.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyLimitConfig {
+    pub name: String,
+    pub max_requests: usize,
+    pub interval: u64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobStoreConfig {
+    pub backend: JobStoreBackend,
+    // only used by file backend.
+    pub path: String,
+    // finished jobs not deleted within ttl are removed. In seconds.
+    pub ttl: u64,
+    pub sweep_interval: u64,
+}
+
+impl JobStoreConfig {
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl)
+    }
+
+    pub fn sweep_interval(&self) -> Duration {
+        Duration::from_secs(self.sweep_interval)
+    }
+}
+
+impl Default for JobStoreConfig {
+    fn default() -> Self {
+        Self {
+            backend: JobStoreBackend::Memory,
+            path: String::from("solver_jobs.json"),
+            ttl: 86400,
+            sweep_interval: 300,
+        }
+    }
+}
+
+/// Cache of PSN query responses. A ttl of 0 disables caching for that query type.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    pub max_entries: usize,
+    // all ttls are in seconds.
+    pub profile_ttl: u64,
+    pub titles_ttl: u64,
+    pub trophy_set_ttl: u64,
+    pub store_ttl: u64,
+}
+
+impl CacheConfig {
+    pub fn ttl_for(&self, query: &PSNQuery) -> u64 {
+        match query {
+            PSNQuery::Profile { .. } => self.profile_ttl,
+            PSNQuery::Titles { .. } => self.titles_ttl,
+            PSNQuery::TrophySet { .. } => self.trophy_set_ttl,
+            PSNQuery::Store { .. } => self.store_ttl,
+        }
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_entries: 10000,
+            profile_ttl: 300,
+            titles_ttl: 300,
+            trophy_set_ttl: 600,
+            store_ttl: 3600,
+        }
+    }
+}
+
+/// PSN accounts are persisted to an encrypted file and restored on start up when key is set.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CredentialsConfig {
+    pub path: String,
+    pub key: Option<String>,
+}
+
+impl Default for CredentialsConfig {
+    fn default() -> Self {
+        Self {
+            path: String::from("accounts.enc"),
+            key: None,
+        }
+    }
+}
+
+/// Api keys created at runtime with `POST /admin/keys`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiKeysConfig {
+    // keys are only kept in memory when no path is given. Only hashes of keys are written.
+    pub path: Option<String>,
+    // PSN queries without an api key are rejected. Otherwise they stay open as before.
+    pub require_for_queries: bool,
+}
+
+impl Default for ApiKeysConfig {
+    fn default() -> Self {
+        Self {
+            path: Some(String::from("api_keys.json")),
+            require_for_queries: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    // serve prometheus metrics at `/metrics`. It's not behind auth so it's off unless asked for.
+    pub enabled: bool,
+}
+
+/// What `/readyz` checks besides pool state.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    // make a request to PSN on every readiness probe.
+    pub check_upstream: bool,
+    // any http answer from this url counts as reachable.
+    pub upstream_url: String,
+    // in seconds.
+    pub upstream_timeout: u64,
+}
+
+impl HealthConfig {
+    pub fn upstream_timeout(&self) -> Duration {
+        Duration::from_secs(self.upstream_timeout)
+    }
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            check_upstream: false,
+            upstream_url: String::from("https://auth.api.sonyentertainmentnetwork.com"),
+            upstream_timeout: 5,
+        }
+    }
+}
+
+/// Json lines log of admin and message operations.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    // entries are only kept in memory when no path is given.
+    pub path: Option<String>,
+    // how many recent entries can be queried with `GET /admin/audit`.
+    pub recent_entries: usize,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            path: Some(String::from("audit.log")),
+            recent_entries: 1000,
+        }
+    }
+}
+
+/// Who can send PSN messages and how often.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MessageConfig {
+    pub auth: MessageAuthMode,
+    // bearer tokens allowed to send messages with key auth. The admin token is always allowed.
+    pub keys: Vec<String>,
+    // a max of 0 turns the throttle off. Intervals are in seconds.
+    pub recipient_max_messages: usize,
+    pub recipient_interval: u64,
+    pub sender_max_messages: usize,
+    pub sender_interval: u64,
+    // online ids are compared case insensitively. An empty allow list allows everyone not denied.
+    pub allow_recipients: Vec<String>,
+    pub deny_recipients: Vec<String>,
+    // the whole multipart form in bytes.
+    pub max_payload_size: usize,
+    // pictures are downscaled to fit these limits. Size is in bytes and dimension in pixels.
+    pub max_image_size: usize,
+    pub max_image_dimension: u32,
+    // pictures with more pixels than this are rejected before they are decoded.
+    pub max_source_pixels: u64,
+    // one message can be sent to this many online ids at most.
+    pub max_recipients: usize,
+    // pause between sends to each recipient. In seconds.
+    pub send_interval: u64,
+}
+
+impl MessageConfig {
+    pub fn send_interval(&self) -> Duration {
+        Duration::from_secs(self.send_interval)
+    }
+
+    pub fn allows_recipient(&self, online_id: &str) -> bool {
+        let matches = |id: &String| id.eq_ignore_ascii_case(online_id);
+
+        if self.deny_recipients.iter().any(matches) {
+            return false;
+        }
+
+        self.allow_recipients.is_empty() || self.allow_recipients.iter().any(matches)
+    }
+}
+
+impl Default for MessageConfig {
+    fn default() -> Self {
+        Self {
+            auth: MessageAuthMode::Admin,
+            keys: Vec::new(),
+            recipient_max_messages: 10,
+            recipient_interval: 3600,
+            sender_max_messages: 60,
+            sender_interval: 3600,
+            allow_recipients: Vec::new(),
+            deny_recipients: Vec::new(),
+            max_payload_size: 10 * 1024 * 1024,
+            max_image_size: 2 * 1024 * 1024,
+            max_image_dimension: 2048,
+            max_source_pixels: 40_000_000,
+            max_recipients: 50,
+            send_interval: 2,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageAuthMode {
+    // only the admin token and api keys with send:message scope.
+    Admin,
+    // also one of message.keys. Deprecated, the keys work as api keys with send:message scope.
+    Key,
+}
+
+impl FromStr for MessageAuthMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "admin" => Ok(MessageAuthMode::Admin),
+            "key" => Ok(MessageAuthMode::Key),
+            _ => Err(String::from("expect admin or key")),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStoreBackend {
+    Memory,
+    File,
+}
+
+impl FromStr for JobStoreBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(JobStoreBackend::Memory),
+            "file" => Ok(JobStoreBackend::File),
+            _ => Err(String::from("expect memory or file")),
+        }
+    }
+}
+
+impl Config {
+    /// Load config with the priority of: command line flags > env vars > config file > defaults.
+    ///
+    /// Command line flags take the form of `--config <path>` and `--<section>.<key> <value>`
+    /// (or `--<section>.<key>=<value>`). e.g: `--server.port 8080 --rate_limiter.enabled true`
+    pub fn load() -> Result<Self, ConfigError> {
+        let flags = parse_flags(env::args().skip(1))?;
+
+        let explicit_path = flags
+            .iter()
+            .find(|(k, _)| k == "config")
+            .map(|(_, v)| v.clone())
+            .or_else(|| env::var("CONFIG_PATH").ok());
+
+        let mut config = match explicit_path {
+            Some(path) => Self::from_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::from_file(DEFAULT_CONFIG_PATH)?
+            }
+            None => Self::default(),
+        };
+
+        for (name, key) in ENV_OVERRIDES.iter() {
+            if let Ok(value) = env::var(name) {
+                config.set(key, &value)?;
+            }
+        }
+
+        for (key, value) in flags.iter().filter(|(k, _)| k != "config") {
+            config.set(key, value)?;
+        }
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, ConfigError> {
+        let content =
+            fs::read_to_string(path).map_err(|e| ConfigError::Io(path.into(), e.to_string()))?;
+
+        toml::from_str(&content).map_err(|e| ConfigError::Parse(path.into(), e.to_string()))
+    }
+
+    /// Override a single value with dotted key. e.g: `server.port`
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        match key {
+            "server.address" => self.server.address = value.into(),
+            "server.port" => self.server.port = parse(key, value)?,
+            "server.cors_origin" => self.server.cors_origin = optional(value),
+            "server.legacy_error_status" => self.server.legacy_error_status = parse(key, value)?,
+            "tls.key_path" => self.tls.key_path = optional(value),
+            "tls.cert_path" => self.tls.cert_path = optional(value),
+            "auth.admin_token" => self.auth.admin_token = value.into(),
+            "solver.api_key" => self.solver.api_key = value.into(),
+            "solver.headless" => self.solver.headless = parse(key, value)?,
+            "solver.navigation_timeout" => self.solver.navigation_timeout = parse(key, value)?,
+            "solver.element_timeout" => self.solver.element_timeout = parse(key, value)?,
+            "solver.captcha_initial_wait" => self.solver.captcha_initial_wait = parse(key, value)?,
+            "solver.captcha_poll_interval" => {
+                self.solver.captcha_poll_interval = parse(key, value)?
+            }
+            "solver.captcha_poll_retries" => self.solver.captcha_poll_retries = parse(key, value)?,
+            "solver.npsso_poll_interval" => self.solver.npsso_poll_interval = parse(key, value)?,
+            "solver.npsso_poll_retries" => self.solver.npsso_poll_retries = parse(key, value)?,
+            "solver.captcha_price" => self.solver.captcha_price = parse(key, value)?,
+            "refresher.interval" => self.refresher.interval = parse(key, value)?,
+            "refresher.refresh_ahead" => self.refresher.refresh_ahead = parse(key, value)?,
+            "refresher.max_retries" => self.refresher.max_retries = parse(key, value)?,
+            "refresher.retry_backoff" => self.refresher.retry_backoff = parse(key, value)?,
+            "rate_limiter.enabled" => self.rate_limiter.enabled = parse(key, value)?,
+            "rate_limiter.max_requests" => self.rate_limiter.max_requests = parse(key, value)?,
+            "rate_limiter.interval" => self.rate_limiter.interval = parse(key, value)?,
+            "rate_limiter.recycle_interval" => {
+                self.rate_limiter.recycle_interval = parse(key, value)?
+            }
+            "job_store.backend" => self.job_store.backend = parse(key, value)?,
+            "job_store.path" => self.job_store.path = value.into(),
+            "job_store.ttl" => self.job_store.ttl = parse(key, value)?,
+            "job_store.sweep_interval" => self.job_store.sweep_interval = parse(key, value)?,
+            "cache.enabled" => self.cache.enabled = parse(key, value)?,
+            "cache.max_entries" => self.cache.max_entries = parse(key, value)?,
+            "cache.profile_ttl" => self.cache.profile_ttl = parse(key, value)?,
+            "cache.titles_ttl" => self.cache.titles_ttl = parse(key, value)?,
+            "cache.trophy_set_ttl" => self.cache.trophy_set_ttl = parse(key, value)?,
+            "cache.store_ttl" => self.cache.store_ttl = parse(key, value)?,
+            "credentials.path" => self.credentials.path = value.into(),
+            "credentials.key" => self.credentials.key = optional(value),
+            "message.auth" => self.message.auth = parse(key, value)?,
+            "message.recipient_max_messages" => {
+                self.message.recipient_max_messages = parse(key, value)?
+            }
+            "message.recipient_interval" => self.message.recipient_interval = parse(key, value)?,
+            "message.sender_max_messages" => self.message.sender_max_messages = parse(key, value)?,
+            "message.sender_interval" => self.message.sender_interval = parse(key, value)?,
+            "message.max_payload_size" => self.message.max_payload_size = parse(key, value)?,
+            "message.max_image_size" => self.message.max_image_size = parse(key, value)?,
+            "message.max_image_dimension" => self.message.max_image_dimension = parse(key, value)?,
+            "message.max_source_pixels" => self.message.max_source_pixels = parse(key, value)?,
+            "message.max_recipients" => self.message.max_recipients = parse(key, value)?,
+            "message.send_interval" => self.message.send_interval = parse(key, value)?,
+            "api_keys.path" => self.api_keys.path = optional(value),
+            "api_keys.require_for_queries" => {
+                self.api_keys.require_for_queries = parse(key, value)?
+            }
+            "audit.path" => self.audit.path = optional(value),
+            "audit.recent_entries" => self.audit.recent_entries = parse(key, value)?,
+            "metrics.enabled" => self.metrics.enabled = parse(key, value)?,
+            "health.check_upstream" => self.health.check_upstream = parse(key, value)?,
+            "health.upstream_url" => self.health.upstream_url = value.into(),
+            "health.upstream_timeout" => self.health.upstream_timeout = parse(key, value)?,
+            _ => return Err(ConfigError::UnknownKey(key.into())),
+        };
+
+        Ok(())
+    }
+
+    /// Check the whole config and report every problem at once.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut errors = Vec::new();
+
+        if self.server.address.is_empty() {
+            errors.push("server.address must not be empty".into());
+        }
+        if self.server.port == 0 {
+            errors.push("server.port must not be 0".into());
+        }
+        if let Some(origin) = self.server.cors_origin.as_ref() {
+            if origin.is_empty() {
+                errors.push("server.cors_origin must not be empty when provided".into());
+            }
+        }
+
+        if self.auth.admin_token.is_empty() {
+            errors.push("auth.admin_token (or BEARER_TOKEN) must be provided".into());
+        }
+
+        match (self.tls.key_path.as_ref(), self.tls.cert_path.as_ref()) {
+            (Some(key), Some(cert)) => {
+                for (name, path) in [("tls.key_path", key), ("tls.cert_path", cert)].iter() {
+                    if !Path::new(path).is_file() {
+                        errors.push(format!("{} points to a missing file: {}", name, path));
+                    }
+                }
+            }
+            (Some(_), None) => errors.push("tls.cert_path is needed to enable ssl".into()),
+            (None, Some(_)) => errors.push("tls.key_path is needed to enable ssl".into()),
+            (None, None) => {}
+        }
+
+        let solver = &self.solver;
+        for (name, value) in [
+            ("solver.navigation_timeout", solver.navigation_timeout),
+            ("solver.element_timeout", solver.element_timeout),
+            ("solver.captcha_poll_interval", solver.captcha_poll_interval),
+            ("solver.npsso_poll_interval", solver.npsso_poll_interval),
+            ("refresher.interval", self.refresher.interval),
+            ("refresher.retry_backoff", self.refresher.retry_backoff),
+            ("job_store.ttl", self.job_store.ttl),
+            ("job_store.sweep_interval", self.job_store.sweep_interval),
+        ]
+        .iter()
+        {
+            if *value == 0 {
+                errors.push(format!("{} must be greater than 0", name));
+            }
+        }
+        if solver.captcha_poll_retries == 0 || solver.npsso_poll_retries == 0 {
+            errors.push("solver poll retries must be greater than 0".into());
+        }
+        if solver.captcha_price.is_nan() || solver.captcha_price < 0.0 {
+            errors.push("solver.captcha_price must not be negative".into());
+        }
+
+        if self.refresher.refresh_ahead <= self.refresher.interval {
+            errors.push("refresher.refresh_ahead must be greater than refresher.interval".into());
+        }
+        if self.refresher.refresh_ahead >= ACCESS_TOKEN_TTL {
+            errors.push(format!(
+                "refresher.refresh_ahead must be less than token lifetime of {} seconds",
+                ACCESS_TOKEN_TTL
+            ));
+        }
+
+        let limiter = &self.rate_limiter;
+        if limiter.enabled {
+            if limiter.max_requests == 0 {
+                errors.push("rate_limiter.max_requests must be greater than 0".into());
+            }
+            if limiter.interval == 0 || limiter.recycle_interval == 0 {
+                errors.push("rate_limiter intervals must be greater than 0".into());
+            }
+            for route in limiter.routes.iter() {
+                if !route.path.starts_with('/') {
+                    errors.push(format!(
+                        "rate_limiter.routes path must start with /: {}",
+                        route.path
+                    ));
+                }
+                if route.max_requests == 0 || route.interval == 0 {
+                    errors.push(format!(
+                        "rate_limiter.routes {} must have non zero limits",
+                        route.path
+                    ));
+                }
+            }
+            for (idx, key) in limiter.keys.iter().enumerate() {
+                if key.name.is_empty() {
+                    errors.push(format!("rate_limiter.keys[{}] name must not be empty", idx));
+                }
+                if key.max_requests == 0 || key.interval == 0 {
+                    errors.push(format!(
+                        "rate_limiter.keys[{}] must have non zero limits",
+                        idx
+                    ));
+                }
+            }
+        }
+
+        if let JobStoreBackend::File = self.job_store.backend {
+            if self.job_store.path.is_empty() {
+                errors.push("job_store.path must not be empty with file backend".into());
+            }
+            if self.credentials.key.is_none() {
+                errors.push(
+                    "job_store.backend = \"file\" needs credentials.key to encrypt npsso codes"
+                        .into(),
+                );
+            }
+        }
+
+        if self.cache.enabled && self.cache.max_entries == 0 {
+            errors.push("cache.max_entries must be greater than 0 when cache is enabled".into());
+        }
+
+        if let Some(key) = self.credentials.key.as_ref() {
+            if key.len() < 16 {
+                errors.push("credentials.key must be at least 16 characters".into());
+            }
+            if self.credentials.path.is_empty() {
+                errors.push("credentials.path must not be empty when key is set".into());
+            }
+        }
+
+        let message = &self.message;
+        if let MessageAuthMode::Key = message.auth {
+            if message.keys.is_empty() {
+                errors.push("message.keys must not be empty with key auth".into());
+            }
+        }
+        for (idx, key) in message.keys.iter().enumerate() {
+            if key.is_empty() {
+                errors.push(format!("message.keys[{}] must not be empty", idx));
+            }
+            if *key == self.auth.admin_token {
+                errors.push(format!("message.keys[{}] must not be the admin token", idx));
+            }
+        }
+        if message.recipient_max_messages > 0 && message.recipient_interval == 0 {
+            errors.push("message.recipient_interval must be greater than 0".into());
+        }
+        if message.sender_max_messages > 0 && message.sender_interval == 0 {
+            errors.push("message.sender_interval must be greater than 0".into());
+        }
+        if message.max_payload_size == 0
+            || message.max_image_size == 0
+            || message.max_image_dimension == 0
+            || message.max_source_pixels == 0
+        {
+            errors.push("message size limits must be greater than 0".into());
+        }
+        if message.max_recipients == 0 {
+            errors.push("message.max_recipients must be greater than 0".into());
+        }
+
+        let health = &self.health;
+        if health.check_upstream {
+            if !health.upstream_url.starts_with("http://")
+                && !health.upstream_url.starts_with("https://")
+            {
+                errors.push("health.upstream_url must be a http url".into());
+            }
+            if health.upstream_timeout == 0 {
+                errors.push("health.upstream_timeout must be greater than 0".into());
+            }
+        }
+
+        if self.audit.recent_entries == 0 {
+            errors.push("audit.recent_entries must be greater than 0".into());
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Validation(errors))
+        }
+    }
+
+    pub fn bind_address(&self) -> String {
+        format!("{}:{}", self.server.address, self.server.port)
+    }
+}
+
+fn parse_flags<I>(args: I) -> Result<Vec<(String, String)>, ConfigError>
+where
+    I: Iterator<Item = String>,
+{
+    let mut flags = Vec::new();
+    let mut args = args;
+
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            return Err(ConfigError::UnknownKey(arg));
+        }
+        let flag = &arg[2..];
+
+        match flag.find('=') {
+            Some(idx) => flags.push((flag[..idx].to_owned(), flag[idx + 1..].to_owned())),
+            None => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| ConfigError::MissingValue(flag.to_owned()))?;
+                flags.push((flag.to_owned(), value));
+            }
+        }
+    }
+
+    Ok(flags)
+}
+
+fn parse<T>(key: &str, value: &str) -> Result<T, ConfigError>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    value.parse::<T>().map_err(|e| ConfigError::Invalid {
+        key: key.into(),
+        value: value.into(),
+        reason: e.to_string(),
+    })
+}
+
+// empty string clears an optional value.
+fn optional(value: &str) -> Option<String> {
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn valid() -> Config {
+        let mut config = Config::default();
+        config.auth.admin_token = String::from("admin_token");
+        config
+    }
+
+    fn validation_errors(config: &Config) -> Vec<String> {
+        match config.validate() {
+            Err(ConfigError::Validation(errors)) => errors,
+            res => panic!("expect validation errors, got {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn parse_file() {
+        let config: Config = toml::from_str(
+            r#"
+            [server]
+            port = 8080
+
+            [rate_limiter]
+            enabled = true
+
+            [[rate_limiter.routes]]
+            path = "/message"
+            max_requests = 5
+            interval = 3600
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.server.port, 8080);
+        assert_eq!(config.server.address, "0.0.0.0");
+        assert!(config.rate_limiter.enabled);
+        assert_eq!(config.rate_limiter.routes[0].path, "/message");
+        assert_eq!(config.refresher.interval, 60);
+        assert!(!config.metrics.enabled);
+    }
+
+    #[test]
+    fn parse_unknown_field() {
+        assert!(toml::from_str::<Config>("[server]\nprot = 8080").is_err());
+        assert!(toml::from_str::<Config>("[servers]\nport = 8080").is_err());
+    }
+
+    #[test]
+    fn set() {
+        let mut config = valid();
+
+        config.set("server.port", "8080").unwrap();
+        config.set("message.auth", "key").unwrap();
+        config.set("api_keys.path", "").unwrap();
+        config.set("metrics.enabled", "true").unwrap();
+
+        assert_eq!(config.server.port, 8080);
+        assert!(matches!(config.message.auth, MessageAuthMode::Key));
+        assert!(config.api_keys.path.is_none());
+        assert!(config.metrics.enabled);
+
+        assert!(matches!(
+            config.set("server.prot", "8080"),
+            Err(ConfigError::UnknownKey(_))
+        ));
+        assert!(matches!(
+            config.set("server.port", "port"),
+            Err(ConfigError::Invalid { .. })
+        ));
+    }
+
+    #[test]
+    fn flags() {
+        let args = vec!["--server.port", "8080", "--cache.enabled=false"]
+            .into_iter()
+            .map(String::from);
+        let flags = parse_flags(args).unwrap();
+
+        assert_eq!(
+            flags,
+            vec![
+                (String::from("server.port"), String::from("8080")),
+                (String::from("cache.enabled"), String::from("false")),
+            ]
+        );
+
+        let args = vec![String::from("--server.port")].into_iter();
+        assert!(matches!(
+            parse_flags(args),
+            Err(ConfigError::MissingValue(_))
+        ));
+    }
+
+    #[test]
+    fn validate_default() {
+        assert!(valid().validate().is_ok());
+
+        let errors = validation_errors(&Config::default());
+        assert_eq!(
+            errors,
+            vec![String::from(
+                "auth.admin_token (or BEARER_TOKEN) must be provided"
+            )]
+        );
+    }
+
+    #[test]
+    fn validate_reports_every_error() {
+        let mut config = valid();
+        config.refresher.refresh_ahead = config.refresher.interval;
+        config.message.max_recipients = 0;
+        config.job_store.backend = JobStoreBackend::File;
+
+        let errors = validation_errors(&config);
+
+        assert_eq!(errors.len(), 3);
+        assert!(errors
+            .iter()
+            .any(|e| e.starts_with("refresher.refresh_ahead")));
+        assert!(errors
+            .iter()
+            .any(|e| e.starts_with("message.max_recipients")));
+        assert!(errors.iter().any(|e| e.contains("credentials.key")));
+    }
+
+    #[test]
+    fn validate_refresh_ahead_within_token_ttl() {
+        let mut config = valid();
+        config.refresher.refresh_ahead = ACCESS_TOKEN_TTL;
+
+        let errors = validation_errors(&config);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("token lifetime"));
+    }
+}
@@ -0,0 +1,287 @@
+use std::fs;
+use std::path::PathBuf;
+
+use openssl::hash::MessageDigest;
+use openssl::pkcs5::pbkdf2_hmac;
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+
+use crate::config::CredentialsConfig;
+use crate::error::PSNServerError;
+
+const SALT_LEN: usize = 16;
+const IV_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const KDF_ITERATIONS: usize = 100_000;
+// bound to every file so a file encrypted for other purpose can't be swapped in.
+const ACCOUNTS_AAD: &[u8] = b"psn_api_service accounts v1";
+pub const SOLVER_JOBS_AAD: &[u8] = b"psn_api_service solver jobs v1";
+
+/// What is needed to bring a PSN account back after restart.
+#[derive(Deserialize, Serialize)]
+pub struct StoredAccount {
+    pub email: String,
+    pub online_id: String,
+    pub region: String,
+    pub language: String,
+    pub refresh_token: String,
+}
+
+/// Encrypted file of PSN account credentials.
+pub struct CredentialStore(SealedFile);
+
+impl CredentialStore {
+    /// None when no credentials key is configured and accounts are not persisted.
+    pub fn new(config: &CredentialsConfig) -> Result<Option<Self>, PSNServerError> {
+        match config.key.as_ref() {
+            Some(secret) => SealedFile::new(&config.path, secret, ACCOUNTS_AAD, "credentials file")
+                .map(|file| Some(Self(file))),
+            None => Ok(None),
+        }
+    }
+
+    pub fn load(&self) -> Result<Vec<StoredAccount>, PSNServerError> {
+        match self.0.read()? {
+            Some(plain) => serde_json::from_slice(&plain).map_err(|e| {
+                PSNServerError::General500(format!("Failed to parse credentials file: {}", e))
+            }),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub fn save(&self, accounts: &[StoredAccount]) -> Result<(), PSNServerError> {
+        let plain = serde_json::to_vec(accounts).map_err(|e| {
+            PSNServerError::General500(format!("Failed to encode credentials: {}", e))
+        })?;
+
+        self.0.write(&plain)
+    }
+}
+
+/// File encrypted with the configured credentials key.
+///
+/// The file is AES-256-GCM encrypted with key derived from the secret by PBKDF2 and laid out as
+/// `salt | iv | tag | ciphertext`.
+///
+/// The salt of an existing file is kept so the key is only derived once.
+pub struct SealedFile {
+    path: PathBuf,
+    salt: [u8; SALT_LEN],
+    key: [u8; 32],
+    aad: &'static [u8],
+    // what the file is called in errors.
+    name: &'static str,
+}
+
+impl SealedFile {
+    pub fn new(
+        path: &str,
+        secret: &str,
+        aad: &'static [u8],
+        name: &'static str,
+    ) -> Result<Self, PSNServerError> {
+        let path = PathBuf::from(path);
+
+        // a missing or short file gets a new salt. `read` reports the short file.
+        let mut salt = [0u8; SALT_LEN];
+        match fs::read(&path) {
+            Ok(content) if content.len() >= SALT_LEN => salt.copy_from_slice(&content[..SALT_LEN]),
+            _ => rand_bytes(&mut salt).map_err(|e| {
+                PSNServerError::General500(format!("Failed to generate salt: {}", e))
+            })?,
+        }
+
+        let key = derive_key(secret, &salt)?;
+
+        Ok(Self {
+            path,
+            salt,
+            key,
+            aad,
+            name,
+        })
+    }
+
+    /// Decrypted content of the file. None when there is no file yet.
+    pub fn read(&self) -> Result<Option<Vec<u8>>, PSNServerError> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+
+        let content = fs::read(&self.path).map_err(|e| {
+            PSNServerError::General500(format!("Failed to read {}: {}", self.name, e))
+        })?;
+
+        if content.len() < SALT_LEN + IV_LEN + TAG_LEN {
+            return Err(PSNServerError::General500(format!(
+                "Failed to read {}: file is corrupted",
+                self.name
+            )));
+        }
+
+        let (iv, rest) = content[SALT_LEN..].split_at(IV_LEN);
+        let (tag, data) = rest.split_at(TAG_LEN);
+
+        decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(iv),
+            self.aad,
+            data,
+            tag,
+        )
+        .map(Some)
+        .map_err(|_| {
+            PSNServerError::General500(format!(
+                "Failed to decrypt {}. Is the key correct?",
+                self.name
+            ))
+        })
+    }
+
+    pub fn write(&self, plain: &[u8]) -> Result<(), PSNServerError> {
+        let mut iv = [0u8; IV_LEN];
+        let mut tag = [0u8; TAG_LEN];
+        let encrypted = rand_bytes(&mut iv)
+            .and_then(|_| {
+                encrypt_aead(
+                    Cipher::aes_256_gcm(),
+                    &self.key,
+                    Some(&iv),
+                    self.aad,
+                    plain,
+                    &mut tag,
+                )
+            })
+            .map_err(|e| {
+                PSNServerError::General500(format!("Failed to encrypt {}: {}", self.name, e))
+            })?;
+
+        let mut content = Vec::with_capacity(SALT_LEN + IV_LEN + TAG_LEN + encrypted.len());
+        content.extend_from_slice(&self.salt);
+        content.extend_from_slice(&iv);
+        content.extend_from_slice(&tag);
+        content.extend_from_slice(&encrypted);
+
+        let tmp = self.path.with_extension("tmp");
+
+        fs::write(&tmp, content)
+            .and_then(|_| fs::rename(&tmp, &self.path))
+            .map_err(|e| {
+                PSNServerError::General500(format!("Failed to write {}: {}", self.name, e))
+            })
+    }
+}
+
+fn derive_key(secret: &str, salt: &[u8]) -> Result<[u8; 32], PSNServerError> {
+    let mut key = [0u8; 32];
+    pbkdf2_hmac(
+        secret.as_bytes(),
+        salt,
+        KDF_ITERATIONS,
+        MessageDigest::sha256(),
+        &mut key,
+    )
+    .map_err(|e| PSNServerError::General500(format!("Failed to derive credentials key: {}", e)))?;
+
+    Ok(key)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct TempPath(String);
+
+    impl TempPath {
+        fn new() -> Self {
+            let path = std::env::temp_dir().join(format!("{}.enc", uuid::Uuid::new_v4()));
+            Self(path.to_string_lossy().into_owned())
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn store(path: &TempPath, key: &str) -> CredentialStore {
+        CredentialStore::new(&CredentialsConfig {
+            path: path.0.clone(),
+            key: Some(key.into()),
+        })
+        .unwrap()
+        .unwrap()
+    }
+
+    fn account() -> StoredAccount {
+        StoredAccount {
+            email: String::from("a@b.c"),
+            online_id: String::from("player"),
+            region: String::from("us"),
+            language: String::from("en"),
+            refresh_token: String::from("refresh_token"),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = TempPath::new();
+
+        assert!(store(&path, "a_long_random_secret")
+            .load()
+            .unwrap()
+            .is_empty());
+
+        store(&path, "a_long_random_secret")
+            .save(&[account()])
+            .unwrap();
+
+        // a new store takes the salt of the file.
+        let accounts = store(&path, "a_long_random_secret").load().unwrap();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].email, "a@b.c");
+        assert_eq!(accounts[0].refresh_token, "refresh_token");
+
+        let content = fs::read(&path.0).unwrap();
+        assert!(!String::from_utf8_lossy(&content).contains("refresh_token"));
+    }
+
+    #[test]
+    fn wrong_key() {
+        let path = TempPath::new();
+
+        store(&path, "a_long_random_secret")
+            .save(&[account()])
+            .unwrap();
+
+        assert!(store(&path, "another_long_secret").load().is_err());
+    }
+
+    #[test]
+    fn other_file_kind() {
+        let path = TempPath::new();
+
+        SealedFile::new(
+            &path.0,
+            "a_long_random_secret",
+            SOLVER_JOBS_AAD,
+            "job store",
+        )
+        .unwrap()
+        .write(b"[]")
+        .unwrap();
+
+        assert!(store(&path, "a_long_random_secret").load().is_err());
+    }
+
+    #[test]
+    fn corrupted_file() {
+        let path = TempPath::new();
+
+        fs::write(&path.0, b"short").unwrap();
+
+        assert!(store(&path, "a_long_random_secret").load().is_err());
+    }
+}
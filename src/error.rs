@@ -1,52 +1,245 @@
 use derive_more::Display;
 use failure::Error as FailureError;
 use ntex::http::client::error::{JsonPayloadError, SendRequestError};
+use ntex::http::{header, StatusCode};
 use ntex::web::{HttpRequest, HttpResponse, WebResponseError};
 use psn_api_rs::psn::PSNError;
 use reqwest::Error as ReqwestError;
 
-#[derive(Debug, Display)]
+use crate::config::Config;
+use crate::rate_limiter::Throttled;
+
+#[derive(Clone, Debug, Display)]
 pub enum PSNServerError {
     #[display(fmt = "Authentication Failed")]
     Authorization,
+    #[display(fmt = "Bad Request: {}", _0)]
+    BadRequest(String),
+    #[display(fmt = "Forbidden: {}", _0)]
+    Forbidden(String),
     #[display(fmt = "Internal Server Error: {}", _0)]
     General500(String),
-    #[display(fmt = "PSN Error: {}", _0)]
-    PSN(String),
+    #[display(fmt = "Not Found: {}", _0)]
+    NotFound(String),
+    #[display(fmt = "Payload Too Large: {}", _0)]
+    PayloadTooLarge(String),
+    #[display(fmt = "PSN Error: {}", _1)]
+    PSN(PSNErrorKind, String),
     #[display(fmt = "Solver Error: {}", _0)]
     Solver(String),
     #[display(fmt = "Request Timeout")]
     TimeOut,
+    #[display(fmt = "Too Many Requests. Retry after {} seconds", reset)]
+    TooManyRequests { limit: usize, reset: u64 },
 }
 
-impl WebResponseError for PSNServerError {
-    fn error_response(&self, _: &HttpRequest) -> HttpResponse {
+impl PSNServerError {
+    pub fn status_code(&self) -> StatusCode {
         match self {
-            PSNServerError::Authorization => {
-                HttpResponse::Ok().json(&ErrorMessage::new(203, &format!("{}", self)))
+            PSNServerError::Authorization => StatusCode::UNAUTHORIZED,
+            PSNServerError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            PSNServerError::Forbidden(_) => StatusCode::FORBIDDEN,
+            PSNServerError::General500(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            PSNServerError::NotFound(_) => StatusCode::NOT_FOUND,
+            PSNServerError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            PSNServerError::PSN(kind, _) => kind.status_code(),
+            PSNServerError::Solver(_) => StatusCode::BAD_GATEWAY,
+            PSNServerError::TimeOut => StatusCode::GATEWAY_TIMEOUT,
+            PSNServerError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+
+    /// Stable machine readable error code. Clients should match on this instead of the message.
+    pub fn code(&self) -> &'static str {
+        match self {
+            PSNServerError::Authorization => "unauthorized",
+            PSNServerError::BadRequest(_) => "bad_request",
+            PSNServerError::Forbidden(_) => "forbidden",
+            PSNServerError::General500(_) => "internal_error",
+            PSNServerError::NotFound(_) => "not_found",
+            PSNServerError::PayloadTooLarge(_) => "payload_too_large",
+            PSNServerError::PSN(kind, _) => kind.code(),
+            PSNServerError::Solver(_) => "solver_error",
+            PSNServerError::TimeOut => "timeout",
+            PSNServerError::TooManyRequests { .. } => "rate_limited",
+        }
+    }
+
+    // the status in json body before real http status codes were used.
+    fn legacy_status(&self) -> u16 {
+        match self {
+            PSNServerError::Authorization => 203,
+            PSNServerError::BadRequest(_) => 400,
+            PSNServerError::Forbidden(_) => 403,
+            PSNServerError::PayloadTooLarge(_) => 413,
+            PSNServerError::NotFound(_) => 404,
+            PSNServerError::PSN(PSNErrorKind::UserNotFound, _) => 404,
+            PSNServerError::TooManyRequests { .. } => 429,
+            _ => 500,
+        }
+    }
+}
+
+impl WebResponseError for PSNServerError {
+    fn error_response(&self, req: &HttpRequest) -> HttpResponse {
+        let legacy = req
+            .app_data::<Config>()
+            .map(|config| config.server.legacy_error_status)
+            .unwrap_or(false);
+
+        let message = match self {
+            PSNServerError::General500(e)
+            | PSNServerError::PSN(_, e)
+            | PSNServerError::Solver(e) => e.to_owned(),
+            _ => format!("{}", self),
+        };
+
+        // throttled requests always get 429 as the rate limiter did from the start.
+        let (status, body_status) = match self {
+            PSNServerError::TooManyRequests { .. } => (self.status_code(), 429),
+            _ if legacy => (StatusCode::OK, self.legacy_status()),
+            _ => (self.status_code(), self.status_code().as_u16()),
+        };
+
+        let mut res = HttpResponse::build(status);
+
+        if let PSNServerError::TooManyRequests { limit, reset } = self {
+            res.header("RateLimit-Limit", limit.to_string())
+                .header("RateLimit-Remaining", "0")
+                .header("RateLimit-Reset", reset.to_string())
+                .header(header::RETRY_AFTER, reset.to_string());
+        }
+
+        res.json(&ErrorMessage {
+            status: body_status,
+            code: self.code(),
+            error: &message,
+        })
+    }
+}
+
+/// What went wrong when talking to PSN.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PSNErrorKind {
+    // the online_id does not exist.
+    UserNotFound,
+    // the user's privacy settings hide the requested data.
+    PrivacyRestricted,
+    // access token of the pool account is expired or revoked.
+    TokenExpired,
+    // PSN is throttling our accounts.
+    RateLimited,
+    // PSN is down or can't be reached.
+    Unavailable,
+    // PSN rejected the request parameters.
+    BadRequest,
+    Unknown,
+}
+
+// error code in PSN error body `{"error":{"code":2105356,"message":"User not found"}}`
+const PSN_USER_NOT_FOUND: u64 = 2_105_356;
+
+impl PSNErrorKind {
+    /// Classify an error answered by PSN from the json body it came with. Known error codes come
+    /// first, then the http status in body. The message text is only the last resort.
+    pub fn from_psn_body(body: &str) -> Self {
+        let (code, status, message) = parse_psn_error_body(body);
+
+        if code == Some(PSN_USER_NOT_FOUND) {
+            return PSNErrorKind::UserNotFound;
+        }
+
+        if let Some(status) = status {
+            let kind = PSNErrorKind::from_status(status);
+            if kind != PSNErrorKind::Unknown {
+                return kind;
             }
+        }
 
-            PSNServerError::PSN(e) => HttpResponse::Ok().json(&ErrorMessage::new(500, e)),
+        // only phrases PSN is known to answer with. Anything else stays unknown.
+        match message.map(|m| m.to_lowercase()).as_deref() {
+            Some("user not found") => PSNErrorKind::UserNotFound,
+            Some("access token expired") | Some("invalid access token") => {
+                PSNErrorKind::TokenExpired
+            }
+            _ => PSNErrorKind::Unknown,
+        }
+    }
+
+    pub fn from_status(status: u16) -> Self {
+        match status {
+            400 => PSNErrorKind::BadRequest,
+            401 => PSNErrorKind::TokenExpired,
+            403 => PSNErrorKind::PrivacyRestricted,
+            404 => PSNErrorKind::UserNotFound,
+            429 => PSNErrorKind::RateLimited,
+            500..=599 => PSNErrorKind::Unavailable,
+            _ => PSNErrorKind::Unknown,
+        }
+    }
 
-            PSNServerError::General500(e) => HttpResponse::Ok().json(&ErrorMessage::new(500, e)),
-            PSNServerError::TimeOut => {
-                HttpResponse::Ok().json(&ErrorMessage::new(500, &format!("{}", self)))
+    pub fn status_code(self) -> StatusCode {
+        match self {
+            PSNErrorKind::UserNotFound => StatusCode::NOT_FOUND,
+            PSNErrorKind::PrivacyRestricted => StatusCode::FORBIDDEN,
+            PSNErrorKind::BadRequest => StatusCode::BAD_REQUEST,
+            PSNErrorKind::RateLimited => StatusCode::SERVICE_UNAVAILABLE,
+            PSNErrorKind::TokenExpired | PSNErrorKind::Unavailable | PSNErrorKind::Unknown => {
+                StatusCode::BAD_GATEWAY
             }
+        }
+    }
 
-            PSNServerError::Solver(e) => HttpResponse::Ok().json(&ErrorMessage::new(500, e)),
+    pub fn code(self) -> &'static str {
+        match self {
+            PSNErrorKind::UserNotFound => "psn_user_not_found",
+            PSNErrorKind::PrivacyRestricted => "psn_privacy_restricted",
+            PSNErrorKind::TokenExpired => "psn_token_expired",
+            PSNErrorKind::RateLimited => "psn_rate_limited",
+            PSNErrorKind::Unavailable => "psn_unavailable",
+            PSNErrorKind::BadRequest => "psn_bad_request",
+            PSNErrorKind::Unknown => "psn_error",
         }
     }
 }
 
+// find the json error body PSN answered with and take its code, http status and message.
+fn parse_psn_error_body(body: &str) -> (Option<u64>, Option<u16>, Option<String>) {
+    #[derive(Deserialize)]
+    struct Body {
+        error: BodyError,
+    }
+
+    #[derive(Deserialize)]
+    struct BodyError {
+        code: Option<u64>,
+        status: Option<u16>,
+        message: Option<String>,
+    }
+
+    let body = body
+        .find('{')
+        .and_then(|idx| serde_json::from_str::<Body>(&body[idx..]).ok());
+
+    match body {
+        Some(body) => (body.error.code, body.error.status, body.error.message),
+        None => (None, None, None),
+    }
+}
+
 #[derive(Serialize)]
 struct ErrorMessage<'a> {
     status: u16,
+    code: &'a str,
     error: &'a str,
 }
 
-impl<'a> ErrorMessage<'a> {
-    fn new(status: u16, error: &'a str) -> Self {
-        ErrorMessage { status, error }
+impl From<Throttled> for PSNServerError {
+    fn from(e: Throttled) -> Self {
+        PSNServerError::TooManyRequests {
+            limit: e.limit,
+            reset: e.reset,
+        }
     }
 }
 
@@ -64,7 +257,18 @@ impl From<JsonPayloadError> for PSNServerError {
 
 impl From<PSNError> for PSNServerError {
     fn from(e: PSNError) -> Self {
-        PSNServerError::PSN(format!("{}", e))
+        let kind = match &e {
+            PSNError::AuthenticationFail => PSNErrorKind::TokenExpired,
+            PSNError::FromPSN(body) => PSNErrorKind::from_psn_body(body),
+            // no status means PSN was never reached.
+            PSNError::FromReqwest(e) => match e.status() {
+                Some(status) => PSNErrorKind::from_status(status.as_u16()),
+                None => PSNErrorKind::Unavailable,
+            },
+            _ => PSNErrorKind::Unknown,
+        };
+
+        PSNServerError::PSN(kind, format!("{}", e))
     }
 }
 
@@ -79,3 +283,89 @@ impl From<ReqwestError> for PSNServerError {
         PSNServerError::Solver(format!("{}", e))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn kind_from_psn_body() {
+        let cases = [
+            (
+                r#"{"error":{"code":2105356,"message":"Not Found"}}"#,
+                PSNErrorKind::UserNotFound,
+            ),
+            // known code comes before status.
+            (
+                r#"{"error":{"code":2105356,"status":403}}"#,
+                PSNErrorKind::UserNotFound,
+            ),
+            // body embedded in an error text.
+            (
+                r#"PSN error: {"error":{"code":1,"status":429,"message":"Too many"}}"#,
+                PSNErrorKind::RateLimited,
+            ),
+            (
+                r#"{"error":{"code":1,"status":503}}"#,
+                PSNErrorKind::Unavailable,
+            ),
+            // unknown status falls back to the message.
+            (
+                r#"{"error":{"status":418,"message":"Access Token Expired"}}"#,
+                PSNErrorKind::TokenExpired,
+            ),
+            (
+                r#"{"error":{"message":"User not found"}}"#,
+                PSNErrorKind::UserNotFound,
+            ),
+            (
+                r#"{"error":{"message":"invalid access token"}}"#,
+                PSNErrorKind::TokenExpired,
+            ),
+            // only exact phrases are matched.
+            (
+                r#"{"error":{"message":"user not found in this region"}}"#,
+                PSNErrorKind::Unknown,
+            ),
+            (r#"{"error":{}}"#, PSNErrorKind::Unknown),
+            ("not json", PSNErrorKind::Unknown),
+            ("", PSNErrorKind::Unknown),
+        ];
+
+        for (body, kind) in cases.iter() {
+            assert_eq!(PSNErrorKind::from_psn_body(body), *kind, "{}", body);
+        }
+    }
+
+    #[test]
+    fn kind_from_status() {
+        let cases = [
+            (400, PSNErrorKind::BadRequest),
+            (401, PSNErrorKind::TokenExpired),
+            (403, PSNErrorKind::PrivacyRestricted),
+            (404, PSNErrorKind::UserNotFound),
+            (429, PSNErrorKind::RateLimited),
+            (500, PSNErrorKind::Unavailable),
+            (599, PSNErrorKind::Unavailable),
+            (200, PSNErrorKind::Unknown),
+            (418, PSNErrorKind::Unknown),
+            (600, PSNErrorKind::Unknown),
+        ];
+
+        for (status, kind) in cases.iter() {
+            assert_eq!(PSNErrorKind::from_status(*status), *kind, "{}", status);
+        }
+    }
+
+    #[test]
+    fn reqwest_error_without_status() {
+        // an invalid url fails before PSN is reached.
+        let e = reqwest::Client::new().get("not a url").build().unwrap_err();
+        assert!(e.status().is_none());
+
+        match PSNServerError::from(PSNError::FromReqwest(e)) {
+            PSNServerError::PSN(kind, _) => assert_eq!(kind, PSNErrorKind::Unavailable),
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+}
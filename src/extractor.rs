@@ -1,26 +1,28 @@
 use std::future::Future;
+use std::net::SocketAddr;
 use std::pin::Pin;
 
-use ntex::http::{Payload, PayloadStream};
+use ntex::http::{HeaderMap, Payload, PayloadStream};
 use ntex::web::{FromRequest, HttpRequest};
 
+use crate::api_keys::{Scope, SharedApiKeys};
 use crate::error::PSNServerError;
-use crate::model::{AdminAuth, SharedGlobalState};
+use crate::model::{AdminAuth, Caller, MessageAuth, SharedGlobalState};
+use crate::rate_limiter::SharedRateLimiter;
 
 impl<F> FromRequest<F> for AdminAuth {
     type Error = PSNServerError;
     type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
 
     fn from_request(req: &HttpRequest, _payload: &mut Payload<PayloadStream>) -> Self::Future {
-        let admin_token = req
+        let state = req
             .app_data::<SharedGlobalState>()
-            .expect("Global State must be initialized")
-            .admin_token();
+            .expect("Global State must be initialized");
 
         let token = req
             .headers()
             .get("Authorization")
-            .map(|v| v.to_str().map(|token| token == admin_token));
+            .map(|v| v.to_str().map(|token| state.is_admin(token)));
 
         Box::pin(async move {
             let is_authenticated = token
@@ -35,3 +37,106 @@ impl<F> FromRequest<F> for AdminAuth {
         })
     }
 }
+
+impl<F> FromRequest<F> for Caller {
+    type Error = PSNServerError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload<PayloadStream>) -> Self::Future {
+        let caller = resolve_caller(req);
+        Box::pin(async move { Ok(caller) })
+    }
+}
+
+impl<F> FromRequest<F> for MessageAuth {
+    type Error = PSNServerError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload<PayloadStream>) -> Self::Future {
+        let res = match resolve_caller(req) {
+            Caller::Admin => Ok(MessageAuth {
+                sender: String::from("admin"),
+            }),
+            Caller::Key(key) => key.require(Scope::SendMessage).map(|_| MessageAuth {
+                sender: format!("api_key:{}", key.id),
+            }),
+            Caller::Anonymous => Err(PSNServerError::Authorization),
+        };
+
+        Box::pin(async move { res })
+    }
+}
+
+// probes and metrics scraping are never throttled.
+const UNLIMITED_PATHS: &[&str] = &["/healthz", "/readyz", "/metrics"];
+
+/// Check a request against the rate limiter before it reaches any route.
+/// Requests with the exact admin token skip the limiter.
+pub(crate) fn rate_limit(
+    limiter: &SharedRateLimiter,
+    state: &SharedGlobalState,
+    api_keys: &SharedApiKeys,
+    path: &str,
+    headers: &HeaderMap,
+    addr: Option<SocketAddr>,
+) -> Result<(), PSNServerError> {
+    if !limiter.enabled() || UNLIMITED_PATHS.contains(&path) {
+        return Ok(());
+    }
+
+    if authorization(headers)
+        .map(|h| state.is_admin(h))
+        .unwrap_or(false)
+    {
+        return Ok(());
+    }
+
+    let key = bearer_token(headers).and_then(|token| api_keys.verify(token));
+    let addr = addr.map(|addr| addr.ip().to_string()).unwrap_or_default();
+
+    limiter
+        .check(path, key.as_ref(), &addr)
+        .map_err(PSNServerError::from)
+}
+
+fn authorization(headers: &HeaderMap) -> Option<&str> {
+    headers.get("Authorization").and_then(|v| v.to_str().ok())
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    authorization(headers).and_then(|h| h.strip_prefix("Bearer "))
+}
+
+// admin token is checked first and must be an exact match.
+fn resolve_caller(req: &HttpRequest) -> Caller {
+    let state = req
+        .app_data::<SharedGlobalState>()
+        .expect("Global State must be initialized");
+
+    if authorization(req.headers())
+        .map(|h| state.is_admin(h))
+        .unwrap_or(false)
+    {
+        return Caller::Admin;
+    }
+
+    let api_keys = req
+        .app_data::<SharedApiKeys>()
+        .expect("Api keys must be initialized");
+
+    match bearer_token(req.headers()).and_then(|token| api_keys.verify(token)) {
+        Some(key) => Caller::Key(key),
+        None => Caller::Anonymous,
+    }
+}
+
+/// How the caller of a request is recorded in audit log, whether it's authenticated or not.
+pub(crate) fn caller_identity(req: &HttpRequest) -> String {
+    match resolve_caller(req) {
+        Caller::Anonymous if req.headers().contains_key("Authorization") => {
+            String::from("unauthenticated")
+        }
+        Caller::Anonymous => String::from("anonymous"),
+        caller => caller.identity(),
+    }
+}
@@ -1,57 +1,116 @@
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
 use futures_util::StreamExt;
-use ntex::web::{HttpRequest, HttpResponse};
+use ntex::http::{header, StatusCode};
+use ntex::web::error::BlockingError;
+use ntex::web::{self, types::Payload, HttpRequest, HttpResponse};
 use ntex_multipart::{Field, Multipart};
-use psn_api_rs::models::MessageThreadResponse;
+use openssl::base64;
+use openssl::rand::rand_bytes;
+use psn_api_rs::models::{
+    MessageThreadResponse, PSNUser, StoreSearchResult, TrophySet, TrophyTitles,
+};
 use psn_api_rs::psn::PSN;
-use psn_api_rs::traits::PSNRequest;
-use psn_api_rs::types::PSNInner;
 use serde::Serialize;
+use serde_json::Value;
 
+use crate::accounts::{build_inner, SharedAccounts};
+use crate::api_keys::{ApiKey, SharedApiKeys};
+use crate::cache::CacheEntry;
 use crate::captcha_solver::CaptchaSolver;
-use crate::error::PSNServerError;
+use crate::config::{HealthConfig, MessageConfig, SolverConfig};
+use crate::error::{PSNErrorKind, PSNServerError};
+use crate::job_store::SharedJobStore;
+use crate::message_store::SharedMessageStore;
+use crate::metrics::SharedMetrics;
 use crate::model::{
-    Npsso, PSNAccount, PSNInnerFailure, PSNInnerInfo, PSNInnerResponse, SharedMap,
-    SolverIdResponse, SolverResponse,
+    unix_timestamp, AccountStatus, ApiKeyCreatedResponse, ApiKeyListResponse, ApiKeyRequest,
+    MessageAuth, MessageJob, MessageRequest, MessageResponse, PSNAccount, PSNAccountListResponse,
+    PSNInnerFailure, PSNInnerInfo, PSNInnerResponse, PSNQuery, ReadinessResponse,
+    RotateTokenRequest, RotateTokenResponse, SharedGlobalState, SolverIdResponse, SolverJob,
+    SolverJobListResponse, SolverJobSummary, SolverResponse, UpstreamCheck,
 };
-use crate::routes::FromAppData;
+use crate::picture::prepare_picture;
 
 pub(crate) fn handle_solver_id(
-    map: &SharedMap,
+    store: &SharedJobStore,
     solver_id: &str,
 ) -> Result<HttpResponse, PSNServerError> {
-    let res = if map.contains(solver_id) {
-        if map.is_ready(solver_id) {
-            SolverIdResponse {
-                status: 200,
-                npsso: map.get(solver_id),
-                error: None,
-            }
-        } else {
-            SolverIdResponse {
-                status: 201,
-                npsso: None,
-                error: Some("Not Ready".into()),
+    let res = match store.get(solver_id) {
+        Some(job) => {
+            if job.is_ready() {
+                // results stay until the job is deleted or expired.
+                SolverIdResponse {
+                    status: 200,
+                    npsso: Some(job.results()),
+                    accounts: Some(job.accounts),
+                    error: None,
+                }
+            } else {
+                // partial results of finished accounts along with progress of the rest.
+                SolverIdResponse {
+                    status: 201,
+                    npsso: Some(job.results()),
+                    accounts: Some(job.accounts),
+                    error: Some("Not Ready".into()),
+                }
             }
         }
-    } else {
-        SolverIdResponse {
-            status: 404,
+        None => {
+            return Err(PSNServerError::NotFound(format!(
+                "solver job {}",
+                solver_id
+            )))
+        }
+    };
+
+    Ok(HttpResponse::Ok().json(&res))
+}
+
+pub(crate) fn handle_delete_solver_job(
+    store: &SharedJobStore,
+    solver_id: &str,
+) -> Result<HttpResponse, PSNServerError> {
+    let res = match store.remove(solver_id)? {
+        Some(_) => SolverIdResponse {
+            status: 200,
             npsso: None,
-            error: Some("Not Found".into()),
+            accounts: None,
+            error: None,
+        },
+        None => {
+            return Err(PSNServerError::NotFound(format!(
+                "solver job {}",
+                solver_id
+            )))
         }
     };
 
     Ok(HttpResponse::Ok().json(&res))
 }
 
+pub(crate) fn handle_list_solver_jobs(
+    store: &SharedJobStore,
+) -> Result<HttpResponse, PSNServerError> {
+    let jobs = store.list().iter().map(SolverJobSummary::from).collect();
+
+    Ok(HttpResponse::Ok().json(&SolverJobListResponse { status: 200, jobs }))
+}
+
 pub(crate) async fn handle_post_admin(
-    api_key: &str,
-    map: SharedMap,
+    config: &SolverConfig,
+    store: SharedJobStore,
     users: Vec<PSNAccount>,
+    auto_install: Option<(PSN, SharedAccounts)>,
+    metrics: SharedMetrics,
 ) -> Result<HttpResponse, PSNServerError> {
     let solver_id = uuid::Uuid::new_v4().to_string();
 
-    let solver = CaptchaSolver::new(api_key.into());
+    store.insert(SolverJob::new(solver_id.clone(), &users))?;
+    metrics.solver_job_started();
+
+    let solver = CaptchaSolver::new(config.clone(), metrics.clone());
 
     let res = HttpResponse::Ok().json(&SolverResponse {
         status: 200,
@@ -59,64 +118,170 @@ pub(crate) async fn handle_post_admin(
     });
 
     ntex_rt::spawn(async move {
-        map.add(solver_id.clone(), Vec::new());
+        let started = Instant::now();
 
-        let mut results = Vec::new();
+        for (idx, user) in users.into_iter().enumerate() {
+            // failing to persist progress or result should not stop the rest accounts.
+            let on_progress = |status| {
+                let _ = store.update(&solver_id, &mut |job| {
+                    job.accounts[idx].set_status(status);
+                });
+            };
 
-        for user in users.into_iter() {
-            let res = solver.get_npsso(&user).await;
+            let res = solver.get_npsso(&user, &on_progress).await;
+            metrics.solver_account_finished(res.is_ok());
 
-            let result = match res {
-                Ok(n) => Npsso {
-                    email: user.email,
-                    npsso: Some(n.npsso),
-                    // ToDo: use datetime here.
-                    expires_at: None,
-                    error: None,
-                },
-                Err(e) => Npsso {
-                    email: user.email,
-                    npsso: None,
-                    expires_at: None,
-                    error: Some(e.to_string()),
-                },
+            // install the account to pool right away so it's usable before the whole job is done.
+            let installed = match (res.as_ref(), auto_install.as_ref()) {
+                (Ok(n), Some((psn, accounts))) => {
+                    let info = PSNInnerInfo {
+                        email: user.email.clone(),
+                        online_id: user.online_id.clone(),
+                        npsso: n.npsso.clone(),
+                        region: user.region.clone(),
+                        language: user.language.clone(),
+                    };
+                    Some(
+                        build_inner(info)
+                            .await
+                            .map(|inner| accounts.add(psn, vec![inner])),
+                    )
+                }
+                _ => None,
             };
-            results.push(result);
+
+            let _ = store.update(&solver_id, &mut |job| {
+                let account = &mut job.accounts[idx];
+                match res.as_ref() {
+                    Ok(n) => {
+                        account.npsso = Some(n.npsso.clone());
+                        // ToDo: use datetime here.
+                        account.expires_at = None;
+                        match installed.as_ref() {
+                            Some(Ok(_)) => account.installed = true,
+                            Some(Err(e)) => {
+                                account.error = Some(format!("Failed to install account: {}", e))
+                            }
+                            None => {}
+                        }
+                        account.set_status(AccountStatus::Done);
+                    }
+                    Err(e) => {
+                        account.error = Some(e.to_string());
+                        account.set_status(AccountStatus::Failed);
+                    }
+                }
+            });
         }
 
-        map.add(solver_id, results);
+        let _ = store.update(&solver_id, &mut |job| {
+            job.finished_at = Some(unix_timestamp());
+        });
+        metrics.solver_job_finished(started.elapsed());
     });
 
     Ok(res)
 }
 
+pub(crate) fn handle_metrics(
+    metrics: &SharedMetrics,
+    accounts: &SharedAccounts,
+    store: &SharedJobStore,
+    config: &SolverConfig,
+) -> Result<HttpResponse, PSNServerError> {
+    let body = metrics.render(&accounts.report(), &store.list(), config.captcha_price);
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body))
+}
+
+/// Ready when pool is resumed and has at least one account with a valid access token.
+pub(crate) async fn handle_readyz(
+    client: &reqwest::Client,
+    accounts: &SharedAccounts,
+    config: &HealthConfig,
+) -> Result<HttpResponse, PSNServerError> {
+    let report = accounts.report();
+    let now = unix_timestamp();
+    let valid_tokens = report
+        .accounts
+        .iter()
+        .filter(|a| a.healthy && a.token_expires_at > now)
+        .count();
+
+    let upstream = if config.check_upstream {
+        Some(check_upstream(client, config).await)
+    } else {
+        None
+    };
+
+    let mut reasons = Vec::new();
+    if report.paused_by_admin {
+        reasons.push("pool is paused by admin");
+    }
+    if report.pool_size == 0 {
+        reasons.push("no healthy account in pool");
+    } else if valid_tokens == 0 {
+        reasons.push("no account with a valid access token");
+    }
+    if let Some(false) = upstream.as_ref().map(|u| u.reachable) {
+        reasons.push("PSN is unreachable");
+    }
+
+    let ready = reasons.is_empty();
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    Ok(HttpResponse::build(status).json(&ReadinessResponse {
+        status: status.as_u16(),
+        ready,
+        paused: report.paused,
+        paused_by_admin: report.paused_by_admin,
+        healthy_accounts: report.pool_size,
+        valid_tokens,
+        upstream,
+        reasons,
+    }))
+}
+
+// any http answer means PSN can be reached. Only connection errors and timeouts count as failure.
+// the probe's own timeout overrides the one of the shared client.
+async fn check_upstream(client: &reqwest::Client, config: &HealthConfig) -> UpstreamCheck {
+    let started = Instant::now();
+
+    let res = client
+        .head(&config.upstream_url)
+        .timeout(config.upstream_timeout())
+        .send()
+        .await
+        .map(|_| ());
+
+    UpstreamCheck {
+        reachable: res.is_ok(),
+        latency_ms: started.elapsed().as_millis() as u64,
+        error: res.err().map(|e| e.to_string()),
+    }
+}
+
 pub(crate) async fn handle_set_npsso(
     npsso: Vec<PSNInnerInfo>,
+    replace: bool,
     psn: &PSN,
+    accounts: &SharedAccounts,
 ) -> Result<HttpResponse, PSNServerError> {
     let mut failure = Vec::new();
     let mut inner = Vec::new();
 
-    let client = PSN::new_client()?;
-
     for n in npsso.into_iter() {
-        let email = n.email;
-        let npsso = n.npsso;
-        let online_id = n.online_id.unwrap_or_else(|| String::from(""));
-        let region = n.region.unwrap_or_else(|| String::from("hk"));
-        let lang = n.language.unwrap_or_else(|| String::from("en"));
-
-        let mut i = PSNInner::new();
-        i.set_email(email.clone())
-            .set_self_online_id(online_id)
-            .set_region(region)
-            .set_lang(lang)
-            .add_npsso(npsso.clone());
-
-        let res = i.gen_access_and_refresh(&client).await;
-
-        match res {
-            Ok(_) => inner.push(i),
+        let email = n.email.clone();
+        let npsso = n.npsso.clone();
+
+        match build_inner(n).await {
+            Ok(i) => inner.push(i),
             Err(e) => failure.push(PSNInnerFailure {
                 email,
                 npsso,
@@ -125,146 +290,792 @@ pub(crate) async fn handle_set_npsso(
         }
     }
 
+    // replace is all or nothing so a typo in one npsso code can't wipe out the working pool.
+    if replace {
+        if failure.is_empty() {
+            accounts.replace(psn, inner);
+        }
+    } else if !inner.is_empty() {
+        accounts.add(psn, inner);
+    }
+
     let failure = if failure.is_empty() {
-        Some(failure)
-    } else {
         None
-    };
-
-    let len = inner.len();
-    let psn_running = if len > 0 {
-        psn.pause_inner();
-        psn.set_psn_inner_max(len);
-        psn.add_psn_inner(inner);
-        psn.clear_inner();
-        psn.resume_inner();
-        true
     } else {
-        false
+        Some(failure)
     };
 
     Ok(HttpResponse::Ok().json(&PSNInnerResponse {
         status: 200,
-        psn_running,
+        psn_running: accounts.healthy_count() > 0,
+        pool_size: accounts.len(),
         failures: failure,
     }))
 }
 
-pub(crate) fn handle_message(req: HttpRequest, mut payload: Multipart) {
-    ntex_rt::spawn(async move {
-        let mut online_id = String::new();
-        let mut msg: Option<String> = None;
-        let mut buf: Vec<u8> = Vec::new();
-
-        while let Some(res) = payload.next().await {
-            match res {
-                Ok(mut field) => {
-                    let mime = field.content_type();
-                    let typ = mime.type_();
-                    let sub_typ = mime.subtype();
-                    match (typ, sub_typ) {
-                        (mime::TEXT, mime::PLAIN) => {
-                            while let Some(chunk) = field.next().await {
-                                match chunk {
-                                    Ok(ref bytes) => {
-                                        if let Ok(txt) = std::str::from_utf8(bytes) {
-                                            match match_field(&field) {
-                                                FieldType::OnlineId => online_id.push_str(txt),
-                                                FieldType::Message => msg = Some(txt.into()),
-                                                _ => break,
-                                            }
-                                        }
-                                    }
-                                    Err(_e) => (),
-                                }
-                            }
-                        }
-                        (mime::IMAGE, _) => match sub_typ {
-                            mime::PNG => {
-                                while let Some(chunk) = field.next().await {
-                                    match chunk {
-                                        Ok(bytes) => match match_field(&field) {
-                                            FieldType::Picture => {
-                                                buf.extend_from_slice(bytes.as_ref())
-                                            }
-                                            _ => break,
-                                        },
-                                        Err(_e) => (),
-                                    }
-                                }
-                            }
-                            mime::JPEG => (),
-                            _ => break,
-                        },
-                        _ => break,
-                    }
+pub(crate) fn handle_list_accounts(
+    accounts: &SharedAccounts,
+) -> Result<HttpResponse, PSNServerError> {
+    Ok(HttpResponse::Ok().json(&PSNAccountListResponse {
+        status: 200,
+        accounts: accounts.list(),
+    }))
+}
+
+pub(crate) fn handle_remove_account(
+    email: &str,
+    psn: &PSN,
+    accounts: &SharedAccounts,
+) -> Result<HttpResponse, PSNServerError> {
+    if accounts.remove(psn, email) {
+        default_200_response()
+    } else {
+        Err(PSNServerError::NotFound(format!("account {}", email)))
+    }
+}
+
+// the old admin token keeps working for at most one day after rotation.
+const MAX_TOKEN_GRACE_PERIOD: u64 = 24 * 3600;
+
+// rotated token is not persisted. The configured one is used again after restart.
+const ROTATED_TOKEN_WARNING: &str =
+    "Admin token is rotated in memory only. Change auth.admin_token \
+    (or BEARER_TOKEN) before the next restart or the old token becomes valid again";
+
+pub(crate) fn handle_rotate_admin_token(
+    state: &SharedGlobalState,
+    token_req: RotateTokenRequest,
+) -> Result<HttpResponse, PSNServerError> {
+    let token = match token_req.token {
+        Some(token) => {
+            if token.len() < 16 || token.chars().any(char::is_whitespace) {
+                return Err(PSNServerError::BadRequest(
+                    "token must be at least 16 characters without whitespace".into(),
+                ));
+            }
+            token
+        }
+        None => {
+            let mut buf = [0u8; 32];
+            rand_bytes(&mut buf).map_err(|e| {
+                PSNServerError::General500(format!("Failed to generate token: {}", e))
+            })?;
+            buf.iter().map(|b| format!("{:02x}", b)).collect()
+        }
+    };
+
+    if token_req.grace_period > MAX_TOKEN_GRACE_PERIOD {
+        return Err(PSNServerError::BadRequest(format!(
+            "grace_period can not be longer than {} seconds",
+            MAX_TOKEN_GRACE_PERIOD
+        )));
+    }
+
+    let previous_expires_at = state.rotate_admin_token(&token, token_req.grace_period);
+
+    Ok(HttpResponse::Ok().json(&RotateTokenResponse {
+        status: 200,
+        admin_token: &token,
+        previous_expires_at,
+        warning: ROTATED_TOKEN_WARNING,
+    }))
+}
+
+pub(crate) fn handle_create_api_key(
+    api_keys: &SharedApiKeys,
+    key_req: ApiKeyRequest,
+) -> Result<HttpResponse, PSNServerError> {
+    if key_req.name.trim().is_empty() {
+        return Err(PSNServerError::BadRequest("name must not be empty".into()));
+    }
+    if key_req.scopes.is_empty() {
+        return Err(PSNServerError::BadRequest(
+            "scopes must not be empty".into(),
+        ));
+    }
+
+    let (key, plain) = api_keys.create(key_req.name, key_req.scopes, key_req.expires_in)?;
+
+    Ok(HttpResponse::Ok().json(&ApiKeyCreatedResponse {
+        status: 200,
+        api_key: &plain,
+        key: key.info(),
+    }))
+}
+
+pub(crate) fn handle_list_api_keys(
+    api_keys: &SharedApiKeys,
+) -> Result<HttpResponse, PSNServerError> {
+    let keys = api_keys.list();
+
+    Ok(HttpResponse::Ok().json(&ApiKeyListResponse {
+        status: 200,
+        keys: keys.iter().map(ApiKey::info).collect(),
+    }))
+}
+
+pub(crate) fn handle_revoke_api_key(
+    api_keys: &SharedApiKeys,
+    id: &str,
+) -> Result<HttpResponse, PSNServerError> {
+    if api_keys.revoke(id)? {
+        default_200_response()
+    } else {
+        Err(PSNServerError::NotFound(format!("api key {}", id)))
+    }
+}
+
+/// A message parsed from json body or multipart form and ready to send.
+pub(crate) struct MessageDraft {
+    pub online_ids: Vec<String>,
+    pub text: Option<String>,
+    pub image: Option<Vec<u8>>,
+}
+
+impl MessageDraft {
+    // validate the fields no matter which form they came in.
+    fn new(
+        online_ids: Vec<String>,
+        text: Option<String>,
+        image: Option<Vec<u8>>,
+        config: &MessageConfig,
+    ) -> Result<Self, PSNServerError> {
+        // online ids are case insensitive so one player is never sent the message twice.
+        let mut recipients: Vec<String> = Vec::with_capacity(online_ids.len());
+        for online_id in online_ids.iter().map(|id| id.trim()) {
+            if online_id.is_empty() {
+                return Err(PSNServerError::BadRequest(
+                    "online_id must not be empty".into(),
+                ));
+            }
+            if !recipients.iter().any(|r| r.eq_ignore_ascii_case(online_id)) {
+                recipients.push(online_id.to_owned());
+            }
+        }
+
+        if recipients.is_empty() {
+            return Err(PSNServerError::BadRequest("online_id is required".into()));
+        }
+        if recipients.len() > config.max_recipients {
+            return Err(PSNServerError::BadRequest(format!(
+                "A message can have at most {} recipients",
+                config.max_recipients
+            )));
+        }
+
+        let text = text.filter(|t| !t.is_empty());
+        let image = image.filter(|i| !i.is_empty());
+        if text.is_none() && image.is_none() {
+            return Err(PSNServerError::BadRequest(
+                "message text or picture is required".into(),
+            ));
+        }
+
+        Ok(MessageDraft {
+            online_ids: recipients,
+            text,
+            image,
+        })
+    }
+}
+
+/// Read a message from either a json body or a multipart form by the request's content type.
+/// The picture is left as sent. See `prepare_message_picture`.
+pub(crate) async fn parse_message(
+    req: &HttpRequest,
+    payload: Payload,
+    config: &MessageConfig,
+) -> Result<MessageDraft, PSNServerError> {
+    let content_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+
+    if content_type.starts_with("application/json") {
+        parse_message_json(payload, config).await
+    } else if content_type.starts_with("multipart/form-data") {
+        parse_message_multipart(Multipart::new(req.headers(), payload), config).await
+    } else {
+        Err(PSNServerError::BadRequest(
+            "Content-Type must be application/json or multipart/form-data".into(),
+        ))
+    }
+}
+
+/// Decode, check and shrink the picture of a message. Called after the recipients and throttles
+/// are checked so rejected messages never cost the image work.
+pub(crate) async fn prepare_message_picture(
+    draft: &mut MessageDraft,
+    config: &MessageConfig,
+) -> Result<(), PSNServerError> {
+    // picture is prepared off the worker thread as decoding can take a while.
+    if let Some(image) = draft.image.take() {
+        let config = config.clone();
+        let image = web::block(move || prepare_picture(image, &config))
+            .await
+            .map_err(|e| match e {
+                BlockingError::Error(e) => e,
+                BlockingError::Canceled => {
+                    PSNServerError::General500("Picture processing was canceled".into())
                 }
-                Err(_e) => (),
+            })?;
+        draft.image = Some(image);
+    }
+
+    Ok(())
+}
+
+async fn parse_message_json(
+    mut payload: Payload,
+    config: &MessageConfig,
+) -> Result<MessageDraft, PSNServerError> {
+    let mut remaining = config.max_payload_size;
+    let mut body = Vec::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|e| PSNServerError::BadRequest(format!("{}", e)))?;
+        remaining = remaining
+            .checked_sub(chunk.len())
+            .ok_or_else(|| PSNServerError::PayloadTooLarge("Message body is too large".into()))?;
+        body.extend_from_slice(chunk.as_ref());
+    }
+
+    let req = serde_json::from_slice::<MessageRequest>(&body)
+        .map_err(|e| PSNServerError::BadRequest(format!("Invalid json body: {}", e)))?;
+
+    let image = match req.image {
+        Some(image) => {
+            // data urls are accepted as is.
+            let encoded = if image.starts_with("data:") {
+                image.split_once(',').map(|(_, b)| b).unwrap_or("")
+            } else {
+                image.as_str()
+            };
+            let decoded = base64::decode_block(encoded.trim())
+                .map_err(|_| PSNServerError::BadRequest("image must be base64 encoded".into()))?;
+            Some(decoded)
+        }
+        None => None,
+    };
+
+    let mut online_ids = req.online_ids;
+    online_ids.extend(req.online_id);
+
+    MessageDraft::new(online_ids, req.text, image, config)
+}
+
+/// Read the whole multipart form. Any malformed or unexpected field is rejected.
+async fn parse_message_multipart(
+    mut payload: Multipart,
+    config: &MessageConfig,
+) -> Result<MessageDraft, PSNServerError> {
+    let mut remaining = config.max_payload_size;
+    let mut online_ids = Vec::new();
+    let mut text: Option<String> = None;
+    let mut image: Option<Vec<u8>> = None;
+
+    while let Some(field) = payload.next().await {
+        let mut field = field.map_err(|e| PSNServerError::BadRequest(format!("{}", e)))?;
+
+        let name = field
+            .content_disposition()
+            .and_then(|cd| cd.get_name().map(String::from));
+        let field_type = match_field(name.as_deref())?;
+        let mime = field.content_type().clone();
+
+        match (field_type, mime.type_()) {
+            (FieldType::OnlineId, mime::TEXT) | (FieldType::Message, mime::TEXT) => {
+                let buf = read_field(&mut field, &mut remaining).await?;
+                let txt = String::from_utf8(buf)
+                    .map_err(|_| PSNServerError::BadRequest("Text fields must be utf-8".into()))?;
+                match field_type {
+                    FieldType::OnlineId => online_ids.push(txt),
+                    _ => text = Some(txt),
+                }
+            }
+            (FieldType::Picture, mime::IMAGE) => {
+                image = Some(read_field(&mut field, &mut remaining).await?);
+            }
+            (FieldType::Picture, _) => {
+                return Err(PSNServerError::BadRequest(format!(
+                    "picture must have an image content type. Got {}",
+                    mime
+                )))
+            }
+            _ => {
+                return Err(PSNServerError::BadRequest(format!(
+                    "Text fields must have a text content type. Got {}",
+                    mime
+                )))
             }
         }
+    }
+
+    MessageDraft::new(online_ids, text, image, config)
+}
 
-        if online_id.is_empty() {
-            return;
+// read a field while counting it against the bytes left of the payload limit.
+async fn read_field(field: &mut Field, remaining: &mut usize) -> Result<Vec<u8>, PSNServerError> {
+    let mut buf = Vec::new();
+    while let Some(chunk) = field.next().await {
+        let chunk = chunk.map_err(|e| PSNServerError::BadRequest(format!("{}", e)))?;
+        *remaining = remaining
+            .checked_sub(chunk.len())
+            .ok_or_else(|| PSNServerError::PayloadTooLarge("Message form is too large".into()))?;
+        buf.extend_from_slice(chunk.as_ref());
+    }
+    Ok(buf)
+}
+
+// send the message to one recipient and return the id of the thread it's sent to.
+async fn send_message(
+    psn: &PSN,
+    online_id: &str,
+    draft: &MessageDraft,
+) -> Result<String, PSNServerError> {
+    let res = psn
+        .send_message_with_buf::<MessageThreadResponse>(
+            online_id,
+            draft.text.as_deref(),
+            draft.image.as_deref(),
+        )
+        .await?;
+
+    Ok(res.thread_id)
+}
+
+// send to every recipient one by one with `send_interval` between them so PSN doesn't see a burst
+// of messages from one account. Return the error of the last failed recipient.
+async fn send_all(
+    psn: &PSN,
+    store: &SharedMessageStore,
+    id: &str,
+    draft: &MessageDraft,
+    send_interval: Duration,
+) -> Result<(), PSNServerError> {
+    let mut last_error = Ok(());
+
+    for (idx, online_id) in draft.online_ids.iter().enumerate() {
+        if idx > 0 {
+            ntex_rt::time::delay_for(send_interval).await;
         }
 
-        if msg.is_none() && buf.is_empty() {
-            return;
+        store.update(id, |message| message.set_sending(idx));
+        let res = send_message(psn, online_id, draft).await;
+        store.update(id, |message| message.finish(idx, &res));
+
+        if let Err(e) = res {
+            last_error = Err(e);
         }
+    }
 
-        let buf = if buf.is_empty() {
-            None
-        } else {
-            Some(buf.as_slice())
-        };
+    last_error
+}
 
-        let psn = req.psn();
-        let _ = psn
-            .send_message_with_buf::<MessageThreadResponse>(&online_id, msg.as_deref(), buf)
-            .await;
-    });
+// a message that would keep the request waiting longer than this between its sends is always sent
+// in background.
+const MAX_SYNC_SEND_WAIT: Duration = Duration::from_secs(30);
+
+/// Send the message and answer with per recipient results. When `is_async` is true the message is
+/// sent in background and the client should look up the results by the returned message id.
+pub(crate) async fn handle_message(
+    psn: PSN,
+    store: SharedMessageStore,
+    sender: String,
+    draft: MessageDraft,
+    send_interval: Duration,
+    is_async: bool,
+) -> Result<HttpResponse, PSNServerError> {
+    let id = uuid::Uuid::new_v4().to_string();
+    let message = MessageJob::new(id.clone(), sender, &draft.online_ids);
+    store.insert(message.clone());
+
+    let too_long = send_interval
+        .checked_mul(draft.online_ids.len().saturating_sub(1) as u32)
+        .map(|wait| wait > MAX_SYNC_SEND_WAIT)
+        .unwrap_or(true);
+
+    if is_async || too_long {
+        ntex_rt::spawn(async move {
+            let _ = send_all(&psn, &store, &id, &draft, send_interval).await;
+        });
+
+        return Ok(HttpResponse::Accepted().json(&MessageResponse {
+            status: 202,
+            message: &message,
+        }));
+    }
+
+    let res = send_all(&psn, &store, &id, &draft, send_interval).await;
+
+    // a single recipient answers with its error directly. Others get results of every recipient.
+    if draft.online_ids.len() == 1 {
+        res?;
+    }
+
+    let message = store
+        .get(&id)
+        .ok_or_else(|| PSNServerError::General500("Message result is lost".into()))?;
+
+    Ok(HttpResponse::Ok().json(&MessageResponse {
+        status: 200,
+        message: &message,
+    }))
+}
+
+// jobs of other senders are reported as not found.
+pub(crate) fn handle_message_status(
+    store: &SharedMessageStore,
+    id: &str,
+    auth: &MessageAuth,
+) -> Result<HttpResponse, PSNServerError> {
+    match store
+        .get(id)
+        .filter(|message| auth.is_admin() || message.sender == auth.sender)
+    {
+        Some(message) => Ok(HttpResponse::Ok().json(&MessageResponse {
+            status: 200,
+            message: &message,
+        })),
+        None => Err(PSNServerError::NotFound(format!("message {}", id))),
+    }
 }
 
+#[derive(Clone, Copy)]
 enum FieldType {
-    None,
     OnlineId,
     Message,
     Picture,
 }
 
-fn match_field(field: &Field) -> FieldType {
-    let disposition = match field.headers().get("content-disposition") {
-        Some(header) => header.to_str().unwrap_or(""),
-        None => return FieldType::None,
-    };
+// field names are matched exactly.
+fn match_field(name: Option<&str>) -> Result<FieldType, PSNServerError> {
+    let name =
+        name.ok_or_else(|| PSNServerError::BadRequest("Form field without a name".into()))?;
 
-    if disposition.contains("online_id") {
-        return FieldType::OnlineId;
+    match name {
+        "online_id" => Ok(FieldType::OnlineId),
+        "message" => Ok(FieldType::Message),
+        "picture" => Ok(FieldType::Picture),
+        _ => Err(PSNServerError::BadRequest(format!(
+            "Unknown form field: {}",
+            name
+        ))),
     }
+}
+
+// thread events are paged by at most this many.
+const MAX_THREAD_LIMIT: usize = 100;
+
+// answers of PSN read outside of psn_api_rs, attached images included, are cut off at this size.
+const MAX_UPSTREAM_BODY_SIZE: usize = 10 * 1024 * 1024;
 
-    if disposition.contains("message") {
-        return FieldType::Message;
+// hosts attached media of messages are served from. Access token is never sent anywhere else.
+const MEDIA_HOSTS: &[&str] = &[".playstation.net", ".playstation.com"];
+
+// threads are read straight from PSN with the access token of the account they belong to.
+const THREADS_URL: &str = "https://us-gmsg.np.community.playstation.net/groupMessaging/v1/threads";
+const THREADS_FIELDS: &str = "threadMembers,threadNameDetail,threadThumbnailDetail,threadProperty,\
+latestMessageEventDetail,latestTakedownEventDetail,newArrivalEventDetail";
+const THREAD_FIELDS: &str = "threadMembers,threadNameDetail,threadThumbnailDetail,threadProperty,\
+latestTakedownEventDetail,newArrivalEventDetail,threadEvents";
+
+pub(crate) async fn handle_message_threads(
+    client: &reqwest::Client,
+    accounts: &SharedAccounts,
+    email: Option<&str>,
+    offset: u32,
+) -> Result<HttpResponse, PSNServerError> {
+    let token = accounts.access_token(email)?;
+
+    let url = threads_url(
+        None,
+        &[("fields", THREADS_FIELDS), ("offset", &offset.to_string())],
+    );
+    let res = upstream_get(client, url, &token, "message threads").await?;
+
+    json_response(read_upstream_body(res).await?)
+}
+
+/// PSN answers with the whole thread so its events are paged here.
+pub(crate) async fn handle_message_thread(
+    client: &reqwest::Client,
+    accounts: &SharedAccounts,
+    email: Option<&str>,
+    thread_id: &str,
+    offset: usize,
+    limit: usize,
+) -> Result<HttpResponse, PSNServerError> {
+    if limit == 0 || limit > MAX_THREAD_LIMIT {
+        return Err(PSNServerError::BadRequest(format!(
+            "limit must be between 1 and {}",
+            MAX_THREAD_LIMIT
+        )));
     }
 
-    if disposition.contains("picture") {
-        return FieldType::Picture;
+    let token = accounts.access_token(email)?;
+    let mut thread = fetch_thread(client, &token, thread_id).await?;
+
+    let events = thread
+        .get_mut("threadEvents")
+        .and_then(Value::as_array_mut)
+        .map(std::mem::take)
+        .unwrap_or_default();
+
+    #[derive(Serialize)]
+    struct ThreadPage {
+        thread: Value,
+        events: Vec<Value>,
+        offset: usize,
+        limit: usize,
+        total: usize,
     }
 
-    FieldType::None
+    let total = events.len();
+    let events = events.into_iter().skip(offset).take(limit).collect();
+
+    json_response(psn_request_body(ThreadPage {
+        thread,
+        events,
+        offset,
+        limit,
+        total,
+    })?)
 }
 
-pub(crate) fn psn_request_response<T: Serialize>(
-    psn_data: T,
+/// Download an image attached to a message of the thread with the access token of its account.
+pub(crate) async fn handle_thread_image(
+    client: &reqwest::Client,
+    accounts: &SharedAccounts,
+    email: Option<&str>,
+    thread_id: &str,
+    url: &str,
 ) -> Result<HttpResponse, PSNServerError> {
+    let parsed = reqwest::Url::parse(url)
+        .map_err(|e| PSNServerError::BadRequest(format!("Invalid image url: {}", e)))?;
+
+    let allowed = parsed.scheme() == "https"
+        && parsed
+            .host_str()
+            .map(|host| MEDIA_HOSTS.iter().any(|h| host.ends_with(h)))
+            .unwrap_or(false);
+    if !allowed {
+        return Err(PSNServerError::BadRequest(
+            "Image url must be a https url of PSN".into(),
+        ));
+    }
+
+    let token = accounts.access_token(email)?;
+
+    // only media attached to an event of the thread can be downloaded through it.
+    let thread = fetch_thread(client, &token, thread_id).await?;
+    let attached = thread
+        .get("threadEvents")
+        .and_then(Value::as_array)
+        .map(|events| {
+            events.iter().any(|event| {
+                event
+                    .pointer("/messageEventDetail/attachedMediaPath")
+                    .and_then(Value::as_str)
+                    == Some(url)
+            })
+        })
+        .unwrap_or(false);
+    if !attached {
+        return Err(PSNServerError::NotFound(format!(
+            "image in thread {}",
+            thread_id
+        )));
+    }
+
+    let res = upstream_get(client, parsed, &token, "image").await?;
+
+    let content_type = res
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("application/octet-stream")
+        .to_owned();
+
+    let body = read_upstream_body(res).await?;
+
+    Ok(HttpResponse::Ok().content_type(content_type).body(body))
+}
+
+async fn fetch_thread(
+    client: &reqwest::Client,
+    token: &str,
+    thread_id: &str,
+) -> Result<Value, PSNServerError> {
+    let url = threads_url(Some(thread_id), &[("fields", THREAD_FIELDS)]);
+    let res = upstream_get(client, url, token, "message thread").await?;
+    let body = read_upstream_body(res).await?;
+
+    serde_json::from_slice(&body).map_err(|e| {
+        PSNServerError::PSN(
+            PSNErrorKind::Unknown,
+            format!("Failed to parse message thread: {}", e),
+        )
+    })
+}
+
+fn threads_url(thread_id: Option<&str>, query: &[(&str, &str)]) -> reqwest::Url {
+    // safe to unwrap as THREADS_URL is a valid https url.
+    let mut url = reqwest::Url::parse(THREADS_URL).unwrap();
+    if let Some(thread_id) = thread_id {
+        url.path_segments_mut().unwrap().push(thread_id);
+    }
+    url.query_pairs_mut().extend_pairs(query);
+    url
+}
+
+// `what` names the resource in the error when PSN answers 404.
+async fn upstream_get(
+    client: &reqwest::Client,
+    url: reqwest::Url,
+    token: &str,
+    what: &str,
+) -> Result<reqwest::Response, PSNServerError> {
+    let res = client
+        .get(url)
+        .bearer_auth(token)
+        .send()
+        .await
+        .map_err(upstream_unavailable)?;
+
+    let status = res.status();
+    if status == reqwest::StatusCode::NOT_FOUND {
+        return Err(PSNServerError::NotFound(what.into()));
+    }
+    if !status.is_success() {
+        return Err(PSNServerError::PSN(
+            PSNErrorKind::from_status(status.as_u16()),
+            format!("PSN answered {} for the {}", status, what),
+        ));
+    }
+
+    Ok(res)
+}
+
+// the body is streamed so an answer over the limit is dropped without being read in full.
+async fn read_upstream_body(mut res: reqwest::Response) -> Result<Bytes, PSNServerError> {
+    let too_large = || {
+        PSNServerError::PSN(
+            PSNErrorKind::Unknown,
+            format!(
+                "PSN answered with more than {} bytes",
+                MAX_UPSTREAM_BODY_SIZE
+            ),
+        )
+    };
+
+    if res.content_length().unwrap_or(0) > MAX_UPSTREAM_BODY_SIZE as u64 {
+        return Err(too_large());
+    }
+
+    let mut body = BytesMut::new();
+    while let Some(chunk) = res.chunk().await.map_err(upstream_unavailable)? {
+        if body.len() + chunk.len() > MAX_UPSTREAM_BODY_SIZE {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body.freeze())
+}
+
+fn upstream_unavailable(e: reqwest::Error) -> PSNServerError {
+    PSNServerError::PSN(PSNErrorKind::Unavailable, format!("{}", e))
+}
+
+fn json_response(body: Bytes) -> Result<HttpResponse, PSNServerError> {
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(body))
+}
+
+pub(crate) async fn handle_psn_query(psn: &PSN, query: &PSNQuery) -> Result<Bytes, PSNServerError> {
+    match query {
+        PSNQuery::Profile { online_id } => {
+            let res = psn.get_profile::<PSNUser>(online_id).await?;
+            psn_request_body(res)
+        }
+        PSNQuery::Titles { online_id, offset } => {
+            let offset = offset.parse::<u32>().unwrap_or(0);
+            let res = psn.get_titles::<TrophyTitles>(online_id, offset).await?;
+            psn_request_body(res)
+        }
+        PSNQuery::TrophySet {
+            online_id,
+            np_communication_id,
+        } => {
+            let res = psn
+                .get_trophy_set::<TrophySet>(online_id, np_communication_id)
+                .await?;
+            psn_request_body(res)
+        }
+        PSNQuery::Store {
+            language,
+            region,
+            name,
+            age,
+        } => {
+            let res = psn
+                .search_store_items::<StoreSearchResult>(language, region, age, name)
+                .await?;
+
+            psn_request_body(res)
+        }
+    }
+}
+
+pub(crate) fn psn_request_body<T: Serialize>(psn_data: T) -> Result<Bytes, PSNServerError> {
     #[derive(Serialize)]
     struct PSNQueryResponse<T> {
         status: u16,
         psn_data: T,
     }
 
-    Ok(HttpResponse::Ok().json(&PSNQueryResponse {
+    serde_json::to_vec(&PSNQueryResponse {
         status: 200,
         psn_data,
-    }))
+    })
+    .map(Bytes::from)
+    .map_err(|e| PSNServerError::General500(format!("{}", e)))
+}
+
+// answer with cached body. 304 if client already has the same body.
+pub(crate) fn cached_response(req: &HttpRequest, entry: &CacheEntry) -> HttpResponse {
+    let max_age = entry.max_age();
+    let cache_control = if max_age > 0 {
+        format!("public, max-age={}", max_age)
+    } else {
+        String::from("no-cache")
+    };
+
+    let not_modified = req
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| {
+            v.split(',')
+                .any(|tag| tag.trim() == entry.etag || tag.trim() == "*")
+        })
+        .unwrap_or(false);
+
+    if not_modified {
+        HttpResponse::NotModified()
+            .header(header::ETAG, entry.etag.as_str())
+            .header(header::CACHE_CONTROL, cache_control)
+            .finish()
+    } else {
+        HttpResponse::Ok()
+            .header(header::ETAG, entry.etag.as_str())
+            .header(header::CACHE_CONTROL, cache_control)
+            .content_type("application/json")
+            .body(entry.body.clone())
+    }
 }
 
 pub(crate) fn default_200_response() -> Result<HttpResponse, PSNServerError> {
@@ -277,3 +1088,91 @@ pub(crate) fn default_200_response() -> Result<HttpResponse, PSNServerError> {
 
     Ok(res)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| String::from(*id)).collect()
+    }
+
+    fn draft(online_ids: &[&str], config: &MessageConfig) -> Result<MessageDraft, PSNServerError> {
+        MessageDraft::new(ids(online_ids), Some("hello".into()), None, config)
+    }
+
+    #[test]
+    fn field_names() {
+        assert!(matches!(
+            match_field(Some("online_id")),
+            Ok(FieldType::OnlineId)
+        ));
+        assert!(matches!(
+            match_field(Some("message")),
+            Ok(FieldType::Message)
+        ));
+        assert!(matches!(
+            match_field(Some("picture")),
+            Ok(FieldType::Picture)
+        ));
+
+        assert!(matches!(
+            match_field(Some("Picture")),
+            Err(PSNServerError::BadRequest(_))
+        ));
+        assert!(matches!(
+            match_field(None),
+            Err(PSNServerError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn draft_recipients() {
+        let config = MessageConfig::default();
+
+        assert!(matches!(
+            draft(&[], &config),
+            Err(PSNServerError::BadRequest(_))
+        ));
+        assert!(matches!(
+            draft(&["player_a", " "], &config),
+            Err(PSNServerError::BadRequest(_))
+        ));
+
+        // online ids are trimmed and deduped case insensitively. The first spelling is kept.
+        let draft = draft(&[" Player_A", "player_a", "player_b", "PLAYER_B "], &config).unwrap();
+        assert_eq!(draft.online_ids, ids(&["Player_A", "player_b"]));
+    }
+
+    #[test]
+    fn draft_max_recipients() {
+        let config = MessageConfig {
+            max_recipients: 2,
+            ..MessageConfig::default()
+        };
+
+        assert!(draft(&["player_a", "player_b"], &config).is_ok());
+        // duplicates don't count against the limit.
+        assert!(draft(&["player_a", "player_b", "PLAYER_A"], &config).is_ok());
+        assert!(matches!(
+            draft(&["player_a", "player_b", "player_c"], &config),
+            Err(PSNServerError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn draft_content() {
+        let config = MessageConfig::default();
+
+        assert!(matches!(
+            MessageDraft::new(
+                ids(&["player_a"]),
+                Some(String::new()),
+                Some(Vec::new()),
+                &config
+            ),
+            Err(PSNServerError::BadRequest(_))
+        ));
+        assert!(MessageDraft::new(ids(&["player_a"]), None, Some(vec![0]), &config).is_ok());
+    }
+}
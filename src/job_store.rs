@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::config::{CredentialsConfig, JobStoreBackend, JobStoreConfig};
+use crate::credentials::{SealedFile, SOLVER_JOBS_AAD};
+use crate::error::PSNServerError;
+use crate::model::{unix_timestamp, AccountStatus, SolverJob};
+
+/// Storage of captcha solver jobs.
+pub trait JobStore: Send + Sync {
+    fn insert(&self, job: SolverJob) -> Result<(), PSNServerError>;
+
+    fn get(&self, id: &str) -> Option<SolverJob>;
+
+    /// Apply `f` to the job with given id. Return false if the job does not exist.
+    fn update(&self, id: &str, f: &mut dyn FnMut(&mut SolverJob)) -> Result<bool, PSNServerError>;
+
+    fn remove(&self, id: &str) -> Result<Option<SolverJob>, PSNServerError>;
+
+    /// All jobs sorted by their creation time.
+    fn list(&self) -> Vec<SolverJob>;
+
+    /// Remove jobs finished before the given unix timestamp. Return the count of removed jobs.
+    fn remove_finished_before(&self, before: u64) -> Result<usize, PSNServerError> {
+        let mut removed = 0;
+
+        for job in self.list() {
+            if job.finished_at.map(|at| at < before).unwrap_or(false) {
+                self.remove(&job.id)?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+}
+
+#[derive(Clone)]
+pub struct SharedJobStore(Arc<dyn JobStore>);
+
+impl SharedJobStore {
+    // the file backend needs a credentials key. It's checked when validating config.
+    pub fn new(
+        config: &JobStoreConfig,
+        credentials: &CredentialsConfig,
+    ) -> Result<Self, PSNServerError> {
+        let store: Arc<dyn JobStore> = match (&config.backend, credentials.key.as_ref()) {
+            (JobStoreBackend::File, Some(secret)) => {
+                Arc::new(FileJobStore::open(&config.path, secret)?)
+            }
+            _ => Arc::new(MemoryJobStore::new()),
+        };
+
+        Ok(Self(store))
+    }
+}
+
+impl std::ops::Deref for SharedJobStore {
+    type Target = dyn JobStore;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+pub struct MemoryJobStore(Mutex<HashMap<String, SolverJob>>);
+
+impl MemoryJobStore {
+    pub fn new() -> Self {
+        Self(Mutex::new(HashMap::new()))
+    }
+}
+
+impl JobStore for MemoryJobStore {
+    fn insert(&self, job: SolverJob) -> Result<(), PSNServerError> {
+        self.0.lock().unwrap().insert(job.id.clone(), job);
+        Ok(())
+    }
+
+    fn get(&self, id: &str) -> Option<SolverJob> {
+        self.0.lock().unwrap().get(id).cloned()
+    }
+
+    fn update(&self, id: &str, f: &mut dyn FnMut(&mut SolverJob)) -> Result<bool, PSNServerError> {
+        Ok(self.0.lock().unwrap().get_mut(id).map(f).is_some())
+    }
+
+    fn remove(&self, id: &str) -> Result<Option<SolverJob>, PSNServerError> {
+        Ok(self.0.lock().unwrap().remove(id))
+    }
+
+    fn list(&self) -> Vec<SolverJob> {
+        let mut jobs = self.0.lock().unwrap().values().cloned().collect::<Vec<_>>();
+        jobs.sort_by_key(|job| job.created_at);
+        jobs
+    }
+}
+
+/// Keep jobs in memory and write them all to a file on every change.
+///
+/// Jobs carry npsso codes so the file is encrypted with the credentials key. It's replaced atomically
+/// by writing to a temporary file and renaming it.
+pub struct FileJobStore {
+    file: SealedFile,
+    jobs: Mutex<HashMap<String, SolverJob>>,
+}
+
+impl FileJobStore {
+    pub fn open(path: &str, secret: &str) -> Result<Self, PSNServerError> {
+        let file = SealedFile::new(path, secret, SOLVER_JOBS_AAD, "job store")?;
+
+        let mut jobs: HashMap<String, SolverJob> = match file.read()? {
+            Some(content) => serde_json::from_slice(&content).map_err(|e| {
+                PSNServerError::General500(format!("Failed to parse job store: {}", e))
+            })?,
+            None => HashMap::new(),
+        };
+
+        // jobs running when the service stopped can not be resumed as we never store passwords.
+        for job in jobs.values_mut() {
+            if job.finished_at.is_none() {
+                job.interrupt();
+            }
+        }
+
+        let store = Self {
+            file,
+            jobs: Mutex::new(jobs),
+        };
+        store.flush(&store.jobs.lock().unwrap())?;
+
+        Ok(store)
+    }
+
+    fn flush(&self, jobs: &HashMap<String, SolverJob>) -> Result<(), PSNServerError> {
+        let content = serde_json::to_vec(jobs)
+            .map_err(|e| PSNServerError::General500(format!("Failed to encode jobs: {}", e)))?;
+
+        self.file.write(&content)
+    }
+}
+
+impl JobStore for FileJobStore {
+    fn insert(&self, job: SolverJob) -> Result<(), PSNServerError> {
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.insert(job.id.clone(), job);
+        self.flush(&jobs)
+    }
+
+    fn get(&self, id: &str) -> Option<SolverJob> {
+        self.jobs.lock().unwrap().get(id).cloned()
+    }
+
+    fn update(&self, id: &str, f: &mut dyn FnMut(&mut SolverJob)) -> Result<bool, PSNServerError> {
+        let mut jobs = self.jobs.lock().unwrap();
+        match jobs.get_mut(id) {
+            Some(job) => {
+                f(job);
+                self.flush(&jobs).map(|_| true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn remove(&self, id: &str) -> Result<Option<SolverJob>, PSNServerError> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.remove(id);
+        if job.is_some() {
+            self.flush(&jobs)?;
+        }
+        Ok(job)
+    }
+
+    fn list(&self) -> Vec<SolverJob> {
+        let mut jobs = self
+            .jobs
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        jobs.sort_by_key(|job| job.created_at);
+        jobs
+    }
+}
+
+impl SolverJob {
+    fn interrupt(&mut self) {
+        for account in self.accounts.iter_mut() {
+            if !account.status.is_finished() {
+                account.set_status(AccountStatus::Failed);
+                account.error = Some("Interrupted by service restart".into());
+            }
+        }
+        self.finished_at = Some(unix_timestamp());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::*;
+    use crate::model::{AccountJob, AccountTransition};
+
+    struct TempPath(String);
+
+    impl TempPath {
+        fn new() -> Self {
+            let path = std::env::temp_dir().join(format!("{}.json", uuid::Uuid::new_v4()));
+            Self(path.to_string_lossy().into_owned())
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn job(id: &str, finished_at: Option<u64>) -> SolverJob {
+        let status = if finished_at.is_some() {
+            AccountStatus::Done
+        } else {
+            AccountStatus::AwaitingCaptcha
+        };
+
+        SolverJob {
+            id: id.into(),
+            created_at: 1,
+            finished_at,
+            accounts: vec![AccountJob {
+                email: String::from("a@b.c"),
+                status,
+                updated_at: 1,
+                history: vec![AccountTransition { status, at: 1 }],
+                npsso: finished_at.map(|_| String::from("npsso_code")),
+                expires_at: None,
+                installed: false,
+                error: None,
+            }],
+        }
+    }
+
+    #[test]
+    fn encrypted_round_trip() {
+        let path = TempPath::new();
+
+        let store = FileJobStore::open(&path.0, "a_long_random_secret").unwrap();
+        store.insert(job("done", Some(10))).unwrap();
+        store.insert(job("running", None)).unwrap();
+        drop(store);
+
+        let content = fs::read(&path.0).unwrap();
+        assert!(!String::from_utf8_lossy(&content).contains("npsso_code"));
+
+        let store = FileJobStore::open(&path.0, "a_long_random_secret").unwrap();
+        let done = store.get("done").unwrap();
+        assert_eq!(done.accounts[0].npsso.as_deref(), Some("npsso_code"));
+
+        // jobs running when the store was closed are failed on open.
+        let running = store.get("running").unwrap();
+        assert!(running.finished_at.is_some());
+        assert_eq!(running.accounts[0].status, AccountStatus::Failed);
+    }
+
+    #[test]
+    fn wrong_key() {
+        let path = TempPath::new();
+
+        let store = FileJobStore::open(&path.0, "a_long_random_secret").unwrap();
+        store.insert(job("done", Some(10))).unwrap();
+        drop(store);
+
+        assert!(FileJobStore::open(&path.0, "another_long_secret").is_err());
+    }
+
+    #[test]
+    fn remove_finished_before() {
+        let path = TempPath::new();
+
+        let store = FileJobStore::open(&path.0, "a_long_random_secret").unwrap();
+        store.insert(job("old", Some(10))).unwrap();
+        store.insert(job("new", Some(20))).unwrap();
+        store.insert(job("running", None)).unwrap();
+
+        assert_eq!(store.remove_finished_before(15).unwrap(), 1);
+
+        let mut ids = store.list().into_iter().map(|j| j.id).collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, vec!["new", "running"]);
+
+        // removed jobs are gone from the file too.
+        drop(store);
+        let store = FileJobStore::open(&path.0, "a_long_random_secret").unwrap();
+        assert!(store.get("old").is_none());
+        assert!(store.get("new").is_some());
+    }
+}
@@ -1,104 +1,219 @@
 #[macro_use]
 extern crate serde_derive;
 
-use std::env;
-
 use ntex::web::{self, App, HttpServer, ServiceConfig};
+use ntex::Service;
 
+use audit::SharedAuditLog;
+use coalescer::Coalescer;
+use config::Config;
+use extractor::rate_limit;
 use routes::*;
 use startup::*;
 
+mod accounts;
+mod api_keys;
+mod audit;
+mod cache;
 mod captcha_solver;
+mod coalescer;
+mod config;
+mod credentials;
 mod error;
 mod extractor;
 mod handler;
+mod job_store;
+mod message_store;
+mod metrics;
 mod model;
+mod picture;
+mod rate_limiter;
 mod routes;
 mod startup;
 
 #[ntex::main]
 async fn main() -> std::io::Result<()> {
     dotenv::dotenv().ok();
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let address = config.bind_address();
+
+    let state = global_builder(&config.auth.admin_token);
+    let job_store = match job_store_builder(&config.job_store, &config.credentials) {
+        Ok(job_store) => job_store,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let api_keys = match api_keys_builder(&config.api_keys, &config.message) {
+        Ok(api_keys) => api_keys,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let audit = match audit_builder(&config.audit) {
+        Ok(audit) => audit,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let http_client = match http_client_builder() {
+        Ok(http_client) => http_client,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let psn = psn_builder().await;
+    let accounts = match accounts_builder(&psn, &config.credentials) {
+        Ok(accounts) => accounts,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    schedule_refresher(psn.clone(), accounts.clone(), &config.refresher);
+    let message_store = message_store_builder();
 
-    let address = env::var("ADDRESS").expect("ADDRESS must be provided in .env");
-    let port = env::var("PORT").expect("PORT must be provided in .env");
-    let admin_token = env::var("BEARER_TOKEN").expect("BEARER_TOKEN must be provided in .env");
-    let api_key = env::var("CAPTCHA_API_KEY").unwrap_or_else(|_| String::from(""));
+    schedule_job_sweeper(job_store.clone(), message_store.clone(), &config.job_store);
 
-    // if CORS_ORIGIN is not provided than no CORS behavior is allowed.
-    let cors_origin = env::var("CORS_ORIGIN").ok();
+    let cache = cache_builder(&config.cache);
+    let metrics = metrics_builder();
+
+    let rate_limiter = rate_limiter_builder(&config.rate_limiter);
+    schedule_rate_limiter_recycle(rate_limiter.clone());
 
     /*
         You would want to enable ssl if you expose the server to internet.
         As some of the APIs would send your authentication info for PSN.
-        So be sure to provide KEY_PATH and CERT_PATH in your .env file if you want to enable it.
+        So be sure to provide tls.key_path and tls.cert_path in your config if you want to enable it.
     */
-    let key_path = env::var("KEY_PATH").ok();
-    let cert_path = env::var("CERT_PATH").ok();
-
-    let (state, map) = global_builder(admin_token.clone(), api_key);
-    let psn = psn_builder().await;
+    let openssl = if config.tls.enabled() {
+        Some(ssl_builder(&config.tls))
+    } else {
+        None
+    };
 
-    schedule_refresher(psn.clone());
+    let app_config = config.clone();
 
-    let simple = match cors_origin {
+    let simple = match config.server.cors_origin {
         Some(cors_origin) => SimpleEither::L(HttpServer::new(move || {
             let cors = cors_builder(&cors_origin);
-
-            // Remove comment if you want to enable build in rate limiter.
-            // let rate_limiter = rate_limiter_builder(&admin_token);
-
+            let limiter = rate_limiter.clone();
+            let limiter_state = state.clone();
+            let limiter_keys = api_keys.clone();
+            // cors wraps the limiter so throttled responses carry cors headers too.
             App::new()
+                .wrap_fn(move |req, srv| {
+                    let checked = rate_limit(
+                        &limiter,
+                        &limiter_state,
+                        &limiter_keys,
+                        req.path(),
+                        req.headers(),
+                        req.peer_addr(),
+                    );
+                    let fut = checked.map(|_| srv.call(req));
+                    async move {
+                        match fut {
+                            Ok(fut) => fut.await,
+                            Err(e) => Err(e.into()),
+                        }
+                    }
+                })
                 .wrap(cors)
-                // Remove comment if you want to enable build in rate limiter.
-                // .wrap(rate_limiter)
+                .app_data(app_config.clone())
                 .app_data(state.clone())
-                .app_data(map.clone())
+                .app_data(rate_limiter.clone())
+                .app_data(job_store.clone())
                 .app_data(psn.clone())
+                .app_data(accounts.clone())
+                .app_data(cache.clone())
+                .app_data(message_store.clone())
+                .app_data(api_keys.clone())
+                .app_data(audit.clone())
+                .app_data(metrics.clone())
+                .app_data(http_client.clone())
+                .app_data(Coalescer::default())
                 .configure(conf_admin)
                 .service(psn_request)
-                .service(web::resource("/message").route(web::post().to(psn_message_request)))
+                .configure(conf_message)
+                .service(message_status)
+                .service(message_threads)
+                .service(message_thread)
+                .service(message_thread_image)
+                .service(routes::metrics)
+                .service(healthz)
+                .service(readyz)
         })),
         None => SimpleEither::R(HttpServer::new(move || {
-            // Remove comment if you want to enable build in rate limiter.
-            // let rate_limiter = rate_limiter_builder(&admin_token);
-
+            let limiter = rate_limiter.clone();
+            let limiter_state = state.clone();
+            let limiter_keys = api_keys.clone();
             App::new()
-                // Remove comment if you want to enable build in rate limiter.
-                // .wrap(rate_limiter)
+                .wrap_fn(move |req, srv| {
+                    let checked = rate_limit(
+                        &limiter,
+                        &limiter_state,
+                        &limiter_keys,
+                        req.path(),
+                        req.headers(),
+                        req.peer_addr(),
+                    );
+                    let fut = checked.map(|_| srv.call(req));
+                    async move {
+                        match fut {
+                            Ok(fut) => fut.await,
+                            Err(e) => Err(e.into()),
+                        }
+                    }
+                })
+                .app_data(app_config.clone())
                 .app_data(state.clone())
-                .app_data(map.clone())
+                .app_data(rate_limiter.clone())
+                .app_data(job_store.clone())
                 .app_data(psn.clone())
+                .app_data(accounts.clone())
+                .app_data(cache.clone())
+                .app_data(message_store.clone())
+                .app_data(api_keys.clone())
+                .app_data(audit.clone())
+                .app_data(metrics.clone())
+                .app_data(http_client.clone())
+                .app_data(Coalescer::default())
                 .configure(conf_admin)
                 .service(psn_request)
-                .service(web::resource("/message").route(web::post().to(psn_message_request)))
+                .configure(conf_message)
+                .service(message_status)
+                .service(message_threads)
+                .service(message_thread)
+                .service(message_thread_image)
+                .service(routes::metrics)
+                .service(healthz)
+                .service(readyz)
         })),
     };
 
     match simple {
-        SimpleEither::L(server) => match key_path {
-            Some(key_path) => {
-                let cert_path = cert_path.expect("Cert path is needed to enable ssl");
-                let openssl = ssl_builder(key_path, cert_path);
-
-                server
-                    .bind_openssl(format!("{}:{}", address, port), openssl)?
-                    .run()
-                    .await
-            }
-            None => server.bind(format!("{}:{}", address, port))?.run().await,
+        SimpleEither::L(server) => match openssl {
+            Some(openssl) => server.bind_openssl(address, openssl)?.run().await,
+            None => server.bind(address)?.run().await,
         },
-        SimpleEither::R(server) => match key_path {
-            Some(key_path) => {
-                let cert_path = cert_path.expect("Cert path is needed to enable ssl");
-                let openssl = ssl_builder(key_path, cert_path);
-
-                server
-                    .bind_openssl(format!("{}:{}", address, port), openssl)?
-                    .run()
-                    .await
-            }
-            None => server.bind(format!("{}:{}", address, port))?.run().await,
+        SimpleEither::R(server) => match openssl {
+            Some(openssl) => server.bind_openssl(address, openssl)?.run().await,
+            None => server.bind(address)?.run().await,
         },
     }
 }
@@ -108,11 +223,50 @@ enum SimpleEither<L, R> {
     R(R),
 }
 
+// every call to admin and message endpoints goes to audit log. Including the denied ones.
+// throttled calls are answered by the rate limiter around the app and never reach here.
 fn conf_admin(cfg: &mut ServiceConfig) {
     cfg.service(
         web::scope("/admin")
+            .wrap_fn(|req, srv| {
+                let fut = srv.call(req);
+                async move {
+                    let res = fut.await;
+                    if let Ok(res) = res.as_ref() {
+                        SharedAuditLog::record_response(res.request(), res.status());
+                    }
+                    res
+                }
+            })
             .service(get_admin)
             .service(post_admin)
-            .service(set_npsso),
+            .service(delete_solver_job)
+            .service(set_npsso)
+            .service(list_accounts)
+            .service(purge_cache)
+            .service(pool_status)
+            .service(remove_account)
+            .service(create_api_key)
+            .service(list_api_keys)
+            .service(revoke_api_key)
+            .service(rotate_admin_token)
+            .service(query_audit_log),
+    );
+}
+
+fn conf_message(cfg: &mut ServiceConfig) {
+    cfg.service(
+        web::resource("/message")
+            .wrap_fn(|req, srv| {
+                let fut = srv.call(req);
+                async move {
+                    let res = fut.await;
+                    if let Ok(res) = res.as_ref() {
+                        SharedAuditLog::record_response(res.request(), res.status());
+                    }
+                    res
+                }
+            })
+            .route(web::post().to(psn_message_request)),
     );
 }
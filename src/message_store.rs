@@ -0,0 +1,43 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::model::MessageJob;
+
+/// Results of sent messages so async sends can be looked up later.
+///
+/// Messages are kept in memory only. Finished ones are swept with the same ttl as solver jobs.
+#[derive(Clone)]
+pub struct SharedMessageStore(Arc<Mutex<HashMap<String, MessageJob>>>);
+
+impl SharedMessageStore {
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(HashMap::new())))
+    }
+
+    pub fn insert(&self, message: MessageJob) {
+        self.0.lock().unwrap().insert(message.id.clone(), message);
+    }
+
+    pub fn get(&self, id: &str) -> Option<MessageJob> {
+        self.0.lock().unwrap().get(id).cloned()
+    }
+
+    /// Apply `f` to the message with given id and return the updated message.
+    pub fn update<F>(&self, id: &str, f: F) -> Option<MessageJob>
+    where
+        F: FnOnce(&mut MessageJob),
+    {
+        self.0.lock().unwrap().get_mut(id).map(|message| {
+            f(message);
+            message.clone()
+        })
+    }
+
+    /// Remove messages finished before the given unix timestamp.
+    pub fn remove_finished_before(&self, before: u64) {
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|_, message| message.finished_at.map(|at| at >= before).unwrap_or(true));
+    }
+}
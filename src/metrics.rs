@@ -0,0 +1,368 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::error::PSNErrorKind;
+use crate::model::{PSNPoolReport, SolverJob};
+
+// upper bounds of latency buckets in seconds. PSN calls take from tens of milliseconds to seconds.
+const REQUEST_BUCKETS: &[f64] = &[0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+// a solver job takes at least the captcha initial wait for every account.
+const SOLVER_BUCKETS: &[f64] = &[30.0, 60.0, 120.0, 300.0, 600.0, 1200.0, 3600.0];
+
+/// Counters and histograms shared by all workers. Rendered in prometheus text format.
+///
+/// Pool and solver job gauges are not kept here. They are read from their sources when rendered.
+#[derive(Clone, Default)]
+pub struct SharedMetrics(Arc<Mutex<Metrics>>);
+
+#[derive(Default)]
+struct Metrics {
+    // (query, http status) => count.
+    requests: BTreeMap<(&'static str, u16), u64>,
+    latency: BTreeMap<&'static str, Histogram>,
+    // error code of PSNErrorKind => count.
+    upstream_errors: BTreeMap<&'static str, u64>,
+    cache_hits: u64,
+    cache_misses: u64,
+    captcha_submitted: u64,
+    captcha_solved: u64,
+    captcha_failed: u64,
+    solver_jobs: u64,
+    solver_accounts_done: u64,
+    solver_accounts_failed: u64,
+    solver_duration: Option<Histogram>,
+}
+
+struct Histogram {
+    buckets: &'static [f64],
+    // counts[i] is the count of observations less or equal to buckets[i].
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Self {
+            buckets,
+            counts: vec![0; buckets.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bucket, count) in self.buckets.iter().zip(self.counts.iter_mut()) {
+            if value <= *bucket {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    // labels are rendered before le and must end with a comma when not empty.
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        for (bucket, count) in self.buckets.iter().zip(self.counts.iter()) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}le=\"{}\"}} {}",
+                name, labels, bucket, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}le=\"+Inf\"}} {}",
+            name, labels, self.count
+        );
+
+        let labels = match labels.trim_end_matches(',') {
+            "" => String::new(),
+            labels => format!("{{{}}}", labels),
+        };
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, self.count);
+    }
+}
+
+impl SharedMetrics {
+    pub fn observe_request(&self, query: &'static str, status: u16, elapsed: Duration) {
+        let mut metrics = self.0.lock().unwrap();
+        *metrics.requests.entry((query, status)).or_insert(0) += 1;
+        metrics
+            .latency
+            .entry(query)
+            .or_insert_with(|| Histogram::new(REQUEST_BUCKETS))
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn upstream_error(&self, kind: PSNErrorKind) {
+        let mut metrics = self.0.lock().unwrap();
+        *metrics.upstream_errors.entry(kind.code()).or_insert(0) += 1;
+    }
+
+    pub fn cache_lookup(&self, hit: bool) {
+        let mut metrics = self.0.lock().unwrap();
+        if hit {
+            metrics.cache_hits += 1;
+        } else {
+            metrics.cache_misses += 1;
+        }
+    }
+
+    pub fn captcha_submitted(&self) {
+        self.0.lock().unwrap().captcha_submitted += 1;
+    }
+
+    /// 2captcha only charges for solved captchas.
+    pub fn captcha_answered(&self, solved: bool) {
+        let mut metrics = self.0.lock().unwrap();
+        if solved {
+            metrics.captcha_solved += 1;
+        } else {
+            metrics.captcha_failed += 1;
+        }
+    }
+
+    pub fn solver_job_started(&self) {
+        self.0.lock().unwrap().solver_jobs += 1;
+    }
+
+    pub fn solver_account_finished(&self, ok: bool) {
+        let mut metrics = self.0.lock().unwrap();
+        if ok {
+            metrics.solver_accounts_done += 1;
+        } else {
+            metrics.solver_accounts_failed += 1;
+        }
+    }
+
+    pub fn solver_job_finished(&self, elapsed: Duration) {
+        self.0
+            .lock()
+            .unwrap()
+            .solver_duration
+            .get_or_insert_with(|| Histogram::new(SOLVER_BUCKETS))
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Render all metrics. `captcha_price` is the cost of one solved captcha.
+    pub fn render(&self, pool: &PSNPoolReport, jobs: &[SolverJob], captcha_price: f64) -> String {
+        let metrics = self.0.lock().unwrap();
+        let mut out = String::with_capacity(4096);
+
+        let requests = metrics
+            .requests
+            .iter()
+            .map(|((query, status), count)| {
+                (
+                    format!("query=\"{}\",status=\"{}\"", query, status),
+                    *count as f64,
+                )
+            })
+            .collect::<Vec<_>>();
+        family(
+            &mut out,
+            "psn_requests_total counter PSN queries answered by query type and http status.",
+            &requests,
+        );
+
+        header(
+            &mut out,
+            "psn_request_duration_seconds histogram Latency of PSN queries by query type.",
+        );
+        for (query, histogram) in metrics.latency.iter() {
+            histogram.render(
+                &mut out,
+                "psn_request_duration_seconds",
+                &format!("query=\"{}\",", query),
+            );
+        }
+
+        let errors = metrics
+            .upstream_errors
+            .iter()
+            .map(|(class, count)| (format!("class=\"{}\"", class), *count as f64))
+            .collect::<Vec<_>>();
+        family(
+            &mut out,
+            "psn_upstream_errors_total counter Errors answered by PSN by class.",
+            &errors,
+        );
+
+        family(
+            &mut out,
+            "psn_cache_requests_total counter Response cache lookups by result.",
+            &[
+                (r#"result="hit""#.into(), metrics.cache_hits as f64),
+                (r#"result="miss""#.into(), metrics.cache_misses as f64),
+            ],
+        );
+
+        let unhealthy = pool.total_accounts - pool.pool_size;
+        family(
+            &mut out,
+            "psn_pool_accounts gauge Accounts loaded by health.",
+            &[
+                (r#"state="healthy""#.into(), pool.pool_size as f64),
+                (r#"state="unhealthy""#.into(), unhealthy as f64),
+            ],
+        );
+        family(
+            &mut out,
+            "psn_pool_paused gauge 1 when pool is paused by admin or has no healthy account.",
+            &[(String::new(), pool.paused as u8 as f64)],
+        );
+        family(
+            &mut out,
+            "psn_refresher_refreshes_total counter Access token refreshes of pool accounts by result.",
+            &[
+                (r#"result="success""#.into(), pool.refresher.successes as f64),
+                (r#"result="failure""#.into(), pool.refresher.failures as f64),
+            ],
+        );
+
+        let running = jobs.iter().filter(|job| job.finished_at.is_none()).count();
+        family(
+            &mut out,
+            "psn_solver_jobs gauge Solver jobs in job store by state.",
+            &[
+                (r#"state="running""#.into(), running as f64),
+                (r#"state="finished""#.into(), (jobs.len() - running) as f64),
+            ],
+        );
+        family(
+            &mut out,
+            "psn_solver_jobs_started_total counter Solver jobs started.",
+            &[(String::new(), metrics.solver_jobs as f64)],
+        );
+        family(
+            &mut out,
+            "psn_solver_accounts_total counter Accounts processed by solver jobs by result.",
+            &[
+                (
+                    r#"result="done""#.into(),
+                    metrics.solver_accounts_done as f64,
+                ),
+                (
+                    r#"result="failed""#.into(),
+                    metrics.solver_accounts_failed as f64,
+                ),
+            ],
+        );
+
+        header(
+            &mut out,
+            "psn_solver_job_duration_seconds histogram Time taken by finished solver jobs.",
+        );
+        if let Some(histogram) = metrics.solver_duration.as_ref() {
+            histogram.render(&mut out, "psn_solver_job_duration_seconds", "");
+        }
+
+        family(
+            &mut out,
+            "psn_captcha_requests_total counter Captchas sent to 2captcha by result.",
+            &[
+                (
+                    r#"result="submitted""#.into(),
+                    metrics.captcha_submitted as f64,
+                ),
+                (r#"result="solved""#.into(), metrics.captcha_solved as f64),
+                (r#"result="failed""#.into(), metrics.captcha_failed as f64),
+            ],
+        );
+        family(
+            &mut out,
+            "psn_captcha_spend_total counter Estimated 2captcha spend of solved captchas.",
+            &[(String::new(), metrics.captcha_solved as f64 * captcha_price)],
+        );
+
+        out
+    }
+}
+
+// `desc` is "<name> <type> <help>".
+fn header(out: &mut String, desc: &str) {
+    let mut parts = desc.splitn(3, ' ');
+    let name = parts.next().unwrap_or_default();
+    let kind = parts.next().unwrap_or_default();
+    let help = parts.next().unwrap_or_default();
+
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+// write a metric with one sample for every set of labels.
+fn family(out: &mut String, desc: &str, samples: &[(String, f64)]) {
+    header(out, desc);
+
+    let name = desc.split(' ').next().unwrap_or_default();
+    for (labels, value) in samples.iter() {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{} {}", name, value);
+        } else {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets() {
+        let mut histogram = Histogram::new(&[0.1, 1.0]);
+
+        histogram.observe(0.05);
+        histogram.observe(0.1);
+        histogram.observe(0.5);
+        histogram.observe(2.0);
+
+        // buckets are cumulative and an observation on a bound falls into that bucket.
+        assert_eq!(histogram.counts, vec![2, 3]);
+        assert_eq!(histogram.count, 4);
+        assert!((histogram.sum - 2.65).abs() < 1e-9);
+    }
+
+    #[test]
+    fn histogram_render() {
+        let mut histogram = Histogram::new(&[0.1, 1.0]);
+        histogram.observe(0.5);
+
+        let mut out = String::new();
+        histogram.render(
+            &mut out,
+            "psn_request_duration_seconds",
+            "query=\"profile\",",
+        );
+
+        assert_eq!(
+            out,
+            "psn_request_duration_seconds_bucket{query=\"profile\",le=\"0.1\"} 0\n\
+             psn_request_duration_seconds_bucket{query=\"profile\",le=\"1\"} 1\n\
+             psn_request_duration_seconds_bucket{query=\"profile\",le=\"+Inf\"} 1\n\
+             psn_request_duration_seconds_sum{query=\"profile\"} 0.5\n\
+             psn_request_duration_seconds_count{query=\"profile\"} 1\n"
+        );
+    }
+
+    #[test]
+    fn histogram_render_without_labels() {
+        let mut histogram = Histogram::new(&[30.0]);
+        histogram.observe(45.0);
+
+        let mut out = String::new();
+        histogram.render(&mut out, "psn_solver_job_duration_seconds", "");
+
+        assert_eq!(
+            out,
+            "psn_solver_job_duration_seconds_bucket{le=\"30\"} 0\n\
+             psn_solver_job_duration_seconds_bucket{le=\"+Inf\"} 1\n\
+             psn_solver_job_duration_seconds_sum 45\n\
+             psn_solver_job_duration_seconds_count 1\n"
+        );
+    }
+}
@@ -1,68 +1,256 @@
-use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use openssl::memcmp;
+use openssl::sha::sha256;
+
+use crate::api_keys::{ApiKey, ApiKeyInfo, Scope};
+use crate::audit::AuditEntry;
+use crate::error::PSNServerError;
 
 #[derive(Clone, Debug)]
 pub struct SharedGlobalState(Arc<GlobalState>);
 
 impl SharedGlobalState {
-    pub fn new(admin_token: String, api_key: String) -> Self {
+    pub fn new(admin_token: &str) -> Self {
         SharedGlobalState(Arc::new(GlobalState {
-            admin_token: Mutex::new(format!("Bearer {}", admin_token)),
-            api_key,
+            admin_token: Mutex::new(AdminToken {
+                current: format!("Bearer {}", admin_token),
+                previous: None,
+            }),
         }))
     }
 
-    pub fn admin_token(&self) -> String {
-        self.0.admin_token.lock().unwrap().clone()
+    /// Check an Authorization header against the admin token and the previous token in its grace
+    /// period. Tokens are compared by their hashes in constant time.
+    pub fn is_admin(&self, header: &str) -> bool {
+        let header = sha256(header.as_bytes());
+        let token = self.0.admin_token.lock().unwrap();
+
+        let previous = token
+            .previous
+            .as_ref()
+            .filter(|(_, expires_at)| *expires_at > unix_timestamp())
+            .map(|(prev, _)| memcmp::eq(&sha256(prev.as_bytes()), &header))
+            .unwrap_or(false);
+
+        memcmp::eq(&sha256(token.current.as_bytes()), &header) | previous
     }
 
-    pub fn api_key(&self) -> &str {
-        &self.0.api_key
+    /// Replace the admin token. The old one keeps working for `grace_period` seconds.
+    /// Return when the old token expires.
+    pub fn rotate_admin_token(&self, new_token: &str, grace_period: u64) -> u64 {
+        let expires_at = unix_timestamp().saturating_add(grace_period);
+        let mut token = self.0.admin_token.lock().unwrap();
+
+        let old = std::mem::replace(&mut token.current, format!("Bearer {}", new_token));
+        token.previous = if grace_period > 0 {
+            Some((old, expires_at))
+        } else {
+            None
+        };
+
+        expires_at
     }
 }
 
 #[derive(Debug)]
 pub struct GlobalState {
-    pub admin_token: Mutex<String>,
-    pub api_key: String,
+    pub admin_token: Mutex<AdminToken>,
 }
 
-#[derive(Clone)]
-pub struct SharedMap(Arc<Mutex<HashMap<String, Vec<Npsso>>>>);
+#[derive(Debug)]
+pub struct AdminToken {
+    // both are full Authorization header values. e.g: "Bearer <token>"
+    current: String,
+    previous: Option<(String, u64)>,
+}
+
+// seconds since unix epoch.
+pub(crate) fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct SolverJob {
+    pub id: String,
+    pub created_at: u64,
+    pub finished_at: Option<u64>,
+    pub accounts: Vec<AccountJob>,
+}
 
-impl SharedMap {
-    pub fn new() -> Self {
-        Self(Arc::new(Mutex::new(HashMap::new())))
+impl SolverJob {
+    pub fn new(id: String, accounts: &[PSNAccount]) -> Self {
+        let now = unix_timestamp();
+
+        SolverJob {
+            id,
+            created_at: now,
+            finished_at: None,
+            accounts: accounts
+                .iter()
+                .map(|account| AccountJob {
+                    email: account.email.clone(),
+                    status: AccountStatus::Queued,
+                    updated_at: now,
+                    history: vec![AccountTransition {
+                        status: AccountStatus::Queued,
+                        at: now,
+                    }],
+                    npsso: None,
+                    expires_at: None,
+                    installed: false,
+                    error: None,
+                })
+                .collect(),
+        }
     }
 
-    pub fn add(&self, key: String, value: Vec<Npsso>) {
-        self.0.lock().unwrap().insert(key, value);
+    pub fn is_ready(&self) -> bool {
+        self.finished_at.is_some()
     }
 
-    pub fn contains(&self, key: &str) -> bool {
-        self.0.lock().unwrap().contains_key(key)
+    // results of accounts already finished. All results when the job is ready.
+    pub fn results(&self) -> Vec<Npsso> {
+        self.accounts
+            .iter()
+            .filter(|account| account.status.is_finished())
+            .map(|account| Npsso {
+                email: account.email.clone(),
+                npsso: account.npsso.clone(),
+                expires_at: account.expires_at.clone(),
+                error: account.error.clone(),
+            })
+            .collect()
     }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct AccountJob {
+    pub email: String,
+    pub status: AccountStatus,
+    pub updated_at: u64,
+    pub history: Vec<AccountTransition>,
+    pub npsso: Option<String>,
+    pub expires_at: Option<String>,
+    // if the account is added to PSN pool by auto_install.
+    #[serde(default)]
+    pub installed: bool,
+    pub error: Option<String>,
+}
+
+impl AccountJob {
+    pub fn set_status(&mut self, status: AccountStatus) {
+        let now = unix_timestamp();
+        self.status = status;
+        self.updated_at = now;
+        self.history.push(AccountTransition { status, at: now });
+    }
+}
 
-    // safe because we always check contains beforehand.
-    pub fn is_ready(&self, key: &str) -> bool {
-        self.0
-            .lock()
-            .unwrap()
-            .get(key)
-            .map(|v| !v.is_empty())
-            .unwrap()
+#[derive(Clone, Deserialize, Serialize)]
+pub struct AccountTransition {
+    pub status: AccountStatus,
+    pub at: u64,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountStatus {
+    Queued,
+    Navigating,
+    AwaitingCaptcha,
+    AwaitingNpsso,
+    Done,
+    Failed,
+}
+
+impl AccountStatus {
+    pub fn is_finished(self) -> bool {
+        matches!(self, AccountStatus::Done | AccountStatus::Failed)
     }
+}
+
+#[derive(Serialize)]
+pub struct SolverJobSummary {
+    pub id: String,
+    pub created_at: u64,
+    pub finished_at: Option<u64>,
+    pub total: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+}
+
+impl From<&SolverJob> for SolverJobSummary {
+    fn from(job: &SolverJob) -> Self {
+        let count = |status| job.accounts.iter().filter(|a| a.status == status).count();
 
-    pub fn get(&self, key: &str) -> Option<Vec<Npsso>> {
-        self.0.lock().unwrap().remove(key)
+        SolverJobSummary {
+            id: job.id.clone(),
+            created_at: job.created_at,
+            finished_at: job.finished_at,
+            total: job.accounts.len(),
+            succeeded: count(AccountStatus::Done),
+            failed: count(AccountStatus::Failed),
+        }
     }
 }
 
+#[derive(Serialize)]
+pub struct SolverJobListResponse {
+    pub status: u16,
+    pub jobs: Vec<SolverJobSummary>,
+}
+
 pub(crate) struct AdminAuth;
 
+// who is calling. Anonymous when no known token is presented.
+pub(crate) enum Caller {
+    Admin,
+    Key(ApiKey),
+    Anonymous,
+}
+
+impl Caller {
+    pub fn require(&self, scope: Scope) -> Result<(), PSNServerError> {
+        match self {
+            Caller::Admin => Ok(()),
+            Caller::Key(key) => key.require(scope),
+            Caller::Anonymous => Err(PSNServerError::Authorization),
+        }
+    }
+
+    // how the caller is recorded in audit log.
+    pub fn identity(&self) -> String {
+        match self {
+            Caller::Admin => String::from("admin"),
+            Caller::Key(key) => format!("api_key:{}", key.id),
+            Caller::Anonymous => String::from("anonymous"),
+        }
+    }
+}
+
+// who is sending a message. Either "admin" or the message key used.
+pub(crate) struct MessageAuth {
+    pub sender: String,
+}
+
+impl MessageAuth {
+    pub fn is_admin(&self) -> bool {
+        self.sender == "admin"
+    }
+}
+
 #[derive(Deserialize)]
 pub struct PSNInnerRequest {
     pub psn_inners: Vec<PSNInnerInfo>,
+    // replace the whole pool instead of adding to it.
+    // The pool is left untouched if any of the given accounts failed.
+    #[serde(default)]
+    pub replace: bool,
 }
 
 #[derive(Deserialize)]
@@ -79,9 +267,52 @@ pub struct PSNInnerInfo {
 pub struct PSNInnerResponse {
     pub status: u16,
     pub psn_running: bool,
+    pub pool_size: usize,
     pub failures: Option<Vec<PSNInnerFailure>>,
 }
 
+#[derive(Serialize)]
+pub struct PSNAccountInfo {
+    pub email: String,
+    pub online_id: String,
+    pub region: String,
+    pub language: String,
+    pub added_at: u64,
+    pub healthy: bool,
+    pub token_expires_at: u64,
+    pub last_refresh_at: Option<u64>,
+    pub last_refresh_ok: Option<bool>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Clone, Default, Serialize)]
+pub struct RefresherStatus {
+    pub last_run_at: Option<u64>,
+    pub last_success_at: Option<u64>,
+    pub last_error: Option<String>,
+    // refreshes of single accounts since start.
+    pub successes: u64,
+    pub failures: u64,
+}
+
+#[derive(Serialize)]
+pub struct PSNPoolReport {
+    pub status: u16,
+    // accounts in rotation.
+    pub pool_size: usize,
+    pub total_accounts: usize,
+    pub paused: bool,
+    pub paused_by_admin: bool,
+    pub refresher: RefresherStatus,
+    pub accounts: Vec<PSNAccountInfo>,
+}
+
+#[derive(Serialize)]
+pub struct PSNAccountListResponse {
+    pub status: u16,
+    pub accounts: Vec<PSNAccountInfo>,
+}
+
 #[derive(Serialize)]
 pub struct PSNInnerFailure {
     pub email: String,
@@ -92,12 +323,19 @@ pub struct PSNInnerFailure {
 #[derive(Deserialize, Serialize)]
 pub(crate) struct SolverRequest {
     pub(crate) accounts: Vec<PSNAccount>,
+    // add accounts to PSN pool as soon as their npsso codes are obtained.
+    #[serde(default)]
+    pub(crate) auto_install: bool,
 }
 
 #[derive(Deserialize, Serialize)]
 pub(crate) struct PSNAccount {
     pub(crate) email: String,
     pub(crate) password: String,
+    // below are only used when auto_install is enabled.
+    pub(crate) online_id: Option<String>,
+    pub(crate) region: Option<String>,
+    pub(crate) language: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -110,6 +348,7 @@ pub struct SolverResponse<'a> {
 #[serde(tag = "query_type")]
 pub enum AdminQuery {
     SolverId { solver_id: String },
+    ListSolverJobs,
     StartService,
     PauseService,
 }
@@ -118,6 +357,7 @@ pub enum AdminQuery {
 pub struct SolverIdResponse {
     pub status: u16,
     pub npsso: Option<Vec<Npsso>>,
+    pub accounts: Option<Vec<AccountJob>>,
     pub error: Option<String>,
 }
 
@@ -135,7 +375,7 @@ pub(crate) struct CaptchaResponse {
     pub(crate) request: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize)]
 #[serde(tag = "query_type")]
 pub enum PSNQuery {
     Profile {
@@ -157,8 +397,394 @@ pub enum PSNQuery {
     },
 }
 
+impl PSNQuery {
+    // online ids are case insensitive on PSN so they are lowercased. Parts are encoded as a json
+    // array so a part containing the separator can't collide with another key.
+    pub fn cache_key(&self) -> String {
+        let parts = match self {
+            PSNQuery::Profile { online_id } => {
+                vec![String::from("profile"), online_id.to_ascii_lowercase()]
+            }
+            PSNQuery::Titles { online_id, offset } => vec![
+                String::from("titles"),
+                online_id.to_ascii_lowercase(),
+                offset.clone(),
+            ],
+            PSNQuery::TrophySet {
+                online_id,
+                np_communication_id,
+            } => vec![
+                String::from("trophy_set"),
+                online_id.to_ascii_lowercase(),
+                np_communication_id.clone(),
+            ],
+            PSNQuery::Store {
+                language,
+                region,
+                age,
+                name,
+            } => vec![
+                String::from("store"),
+                language.clone(),
+                region.clone(),
+                age.clone(),
+                name.clone(),
+            ],
+        };
+
+        // safe to unwrap as a list of strings always serializes.
+        serde_json::to_string(&parts).unwrap()
+    }
+
+    // scope an api key needs for the query. Store search needs none.
+    pub fn scope(&self) -> Option<Scope> {
+        match self {
+            PSNQuery::Profile { .. } => Some(Scope::ReadProfile),
+            PSNQuery::Titles { .. } | PSNQuery::TrophySet { .. } => Some(Scope::ReadTrophies),
+            PSNQuery::Store { .. } => None,
+        }
+    }
+
+    // label of the query in metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            PSNQuery::Profile { .. } => "profile",
+            PSNQuery::Titles { .. } => "titles",
+            PSNQuery::TrophySet { .. } => "trophy_set",
+            PSNQuery::Store { .. } => "store",
+        }
+    }
+
+    pub fn online_id(&self) -> Option<&str> {
+        match self {
+            PSNQuery::Profile { online_id }
+            | PSNQuery::Titles { online_id, .. }
+            | PSNQuery::TrophySet { online_id, .. } => Some(online_id),
+            PSNQuery::Store { .. } => None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct CachePurgeResponse {
+    pub status: u16,
+    pub purged: usize,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct PSNNpssoResponse {
     pub npsso: String,
     pub expires_in: i32,
 }
+
+#[derive(Deserialize)]
+pub struct MessageQuery {
+    // answer right away and send the message in background.
+    #[serde(default, rename = "async")]
+    pub is_async: bool,
+}
+
+/// Json form of a message. `image` is base64 encoded.
+///
+/// Recipients can be given by `online_id`, `online_ids` or both. The message is sent to every one
+/// of them in their own thread.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MessageRequest {
+    pub online_id: Option<String>,
+    #[serde(default)]
+    pub online_ids: Vec<String>,
+    pub text: Option<String>,
+    pub image: Option<String>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct MessageJob {
+    pub id: String,
+    // the sender created this job. Only it and admin can read the job.
+    #[serde(skip)]
+    pub sender: String,
+    pub status: MessageStatus,
+    pub created_at: u64,
+    pub finished_at: Option<u64>,
+    pub recipients: Vec<RecipientResult>,
+}
+
+impl MessageJob {
+    pub fn new(id: String, sender: String, online_ids: &[String]) -> Self {
+        MessageJob {
+            id,
+            sender,
+            status: MessageStatus::Sending,
+            created_at: unix_timestamp(),
+            finished_at: None,
+            recipients: online_ids
+                .iter()
+                .map(|online_id| RecipientResult {
+                    online_id: online_id.clone(),
+                    status: MessageStatus::Queued,
+                    sent_at: None,
+                    thread_id: None,
+                    error: None,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn set_sending(&mut self, idx: usize) {
+        self.recipients[idx].status = MessageStatus::Sending;
+    }
+
+    // result of one recipient. The whole message is finished along with the last one.
+    pub fn finish(&mut self, idx: usize, res: &Result<String, PSNServerError>) {
+        let now = unix_timestamp();
+        let recipient = &mut self.recipients[idx];
+
+        match res {
+            Ok(thread_id) => {
+                recipient.status = MessageStatus::Sent;
+                recipient.thread_id = Some(thread_id.clone());
+            }
+            Err(e) => {
+                recipient.status = MessageStatus::Failed;
+                recipient.error = Some(format!("{}", e));
+            }
+        }
+        recipient.sent_at = Some(now);
+
+        if self.recipients.iter().all(|r| r.status.is_finished()) {
+            let sent = self
+                .recipients
+                .iter()
+                .filter(|r| r.status == MessageStatus::Sent)
+                .count();
+
+            self.status = if sent == self.recipients.len() {
+                MessageStatus::Sent
+            } else if sent == 0 {
+                MessageStatus::Failed
+            } else {
+                MessageStatus::PartiallySent
+            };
+            self.finished_at = Some(now);
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct RecipientResult {
+    pub online_id: String,
+    pub status: MessageStatus,
+    pub sent_at: Option<u64>,
+    pub thread_id: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageStatus {
+    Queued,
+    Sending,
+    Sent,
+    PartiallySent,
+    Failed,
+}
+
+impl MessageStatus {
+    pub fn is_finished(&self) -> bool {
+        !matches!(self, MessageStatus::Queued | MessageStatus::Sending)
+    }
+}
+
+#[derive(Serialize)]
+pub struct MessageResponse<'a> {
+    pub status: u16,
+    pub message: &'a MessageJob,
+}
+
+#[derive(Deserialize)]
+pub struct ThreadsQuery {
+    #[serde(default)]
+    pub offset: u32,
+    // the pool account the threads belong to. Required when the pool has more than one account.
+    pub email: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ThreadQuery {
+    #[serde(default)]
+    pub offset: usize,
+    #[serde(default = "default_thread_limit")]
+    pub limit: usize,
+    pub email: Option<String>,
+}
+
+fn default_thread_limit() -> usize {
+    20
+}
+
+#[derive(Deserialize)]
+pub struct ThreadImageQuery {
+    // attachedMediaPath of a thread event.
+    pub url: String,
+    pub email: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeyRequest {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    // seconds until the key expires. Never expires when not given.
+    pub expires_in: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct ApiKeyCreatedResponse<'a> {
+    pub status: u16,
+    // the plain key. It's only shown once.
+    pub api_key: &'a str,
+    pub key: ApiKeyInfo<'a>,
+}
+
+#[derive(Serialize)]
+pub struct ApiKeyListResponse<'a> {
+    pub status: u16,
+    pub keys: Vec<ApiKeyInfo<'a>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RotateTokenRequest {
+    // a random token is generated when not given.
+    pub token: Option<String>,
+    // seconds the old token keeps working.
+    #[serde(default)]
+    pub grace_period: u64,
+}
+
+#[derive(Serialize)]
+pub struct RotateTokenResponse<'a> {
+    pub status: u16,
+    pub admin_token: &'a str,
+    pub previous_expires_at: u64,
+    pub warning: &'static str,
+}
+
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    pub action: Option<String>,
+    pub actor: Option<String>,
+    // unix timestamp.
+    pub since: Option<u64>,
+    #[serde(default = "default_audit_limit")]
+    pub limit: usize,
+}
+
+fn default_audit_limit() -> usize {
+    100
+}
+
+#[derive(Serialize)]
+pub struct AuditListResponse {
+    pub status: u16,
+    pub entries: Vec<AuditEntry>,
+}
+
+#[derive(Serialize)]
+pub struct HealthResponse {
+    pub status: u16,
+    pub alive: bool,
+}
+
+#[derive(Serialize)]
+pub struct ReadinessResponse {
+    pub status: u16,
+    pub ready: bool,
+    pub paused: bool,
+    pub paused_by_admin: bool,
+    pub healthy_accounts: usize,
+    // healthy accounts with an access token not expired yet.
+    pub valid_tokens: usize,
+    // only present when upstream check is enabled.
+    pub upstream: Option<UpstreamCheck>,
+    // why the service is not ready. Empty when it is.
+    pub reasons: Vec<&'static str>,
+}
+
+#[derive(Serialize)]
+pub struct UpstreamCheck {
+    pub reachable: bool,
+    pub latency_ms: u64,
+    pub error: Option<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn admin_token() {
+        let state = SharedGlobalState::new("admin_token");
+
+        assert!(state.is_admin("Bearer admin_token"));
+        assert!(!state.is_admin("admin_token"));
+        assert!(!state.is_admin("Bearer admin_toke"));
+        assert!(!state.is_admin(""));
+    }
+
+    #[test]
+    fn rotate_with_grace_period() {
+        let state = SharedGlobalState::new("old_token");
+
+        let expires_at = state.rotate_admin_token("new_token", 300);
+        assert!(expires_at >= unix_timestamp() + 299);
+
+        assert!(state.is_admin("Bearer new_token"));
+        assert!(state.is_admin("Bearer old_token"));
+    }
+
+    #[test]
+    fn rotate_without_grace_period() {
+        let state = SharedGlobalState::new("old_token");
+
+        state.rotate_admin_token("new_token", 0);
+
+        assert!(state.is_admin("Bearer new_token"));
+        assert!(!state.is_admin("Bearer old_token"));
+    }
+
+    #[test]
+    fn grace_period_expiry() {
+        let state = SharedGlobalState::new("old_token");
+        state.rotate_admin_token("new_token", 300);
+
+        // move the end of grace period to the past.
+        if let Some((_, expires_at)) = state.0.admin_token.lock().unwrap().previous.as_mut() {
+            *expires_at = unix_timestamp() - 1;
+        }
+
+        assert!(state.is_admin("Bearer new_token"));
+        assert!(!state.is_admin("Bearer old_token"));
+    }
+
+    #[test]
+    fn rotate_again_drops_first_token() {
+        let state = SharedGlobalState::new("first_token");
+
+        state.rotate_admin_token("second_token", 300);
+        state.rotate_admin_token("third_token", 300);
+
+        assert!(!state.is_admin("Bearer first_token"));
+        assert!(state.is_admin("Bearer second_token"));
+        assert!(state.is_admin("Bearer third_token"));
+    }
+
+    #[test]
+    fn grace_period_does_not_overflow() {
+        let state = SharedGlobalState::new("old_token");
+
+        assert_eq!(state.rotate_admin_token("new_token", u64::MAX), u64::MAX);
+        assert!(state.is_admin("Bearer old_token"));
+    }
+}
@@ -0,0 +1,198 @@
+use std::io::Cursor;
+
+use image::imageops::FilterType;
+use image::io::Reader;
+use image::{DynamicImage, GenericImageView, ImageFormat, ImageOutputFormat};
+
+use crate::config::MessageConfig;
+use crate::error::PSNServerError;
+
+// formats decoded from message attachments.
+const ACCEPTED_FORMATS: &[ImageFormat] = &[
+    ImageFormat::Png,
+    ImageFormat::Jpeg,
+    ImageFormat::Gif,
+    ImageFormat::Bmp,
+];
+
+// quality of re-encoded jpeg pictures.
+const JPEG_QUALITY: u8 = 85;
+
+// give up shrinking an image that still doesn't fit after this many halvings.
+const MAX_SHRINKS: usize = 4;
+
+/// Decode a picture attachment and make it fit PSN limits.
+///
+/// The format is sniffed from the bytes rather than trusting the declared content type. The
+/// dimensions are read from the header before decoding so a small file can't decode into a huge
+/// bitmap. Every picture is decoded so a broken one is rejected here. Pictures already fitting
+/// the limits are sent as they are. Others are downscaled and re-encoded as jpeg when they were
+/// jpeg and as png otherwise.
+///
+/// Decoding and encoding are cpu heavy. Call it on a blocking thread.
+pub(crate) fn prepare_picture(
+    buf: Vec<u8>,
+    config: &MessageConfig,
+) -> Result<Vec<u8>, PSNServerError> {
+    let format = image::guess_format(&buf)
+        .ok()
+        .filter(|f| ACCEPTED_FORMATS.contains(f))
+        .ok_or_else(|| {
+            PSNServerError::BadRequest("Picture must be a png, jpeg, gif or bmp image".into())
+        })?;
+
+    let (width, height) = Reader::with_format(Cursor::new(&buf), format)
+        .into_dimensions()
+        .map_err(|e| PSNServerError::BadRequest(format!("Can not decode picture: {}", e)))?;
+
+    if u64::from(width) * u64::from(height) > config.max_source_pixels {
+        return Err(PSNServerError::PayloadTooLarge(format!(
+            "Picture can not have more than {} pixels",
+            config.max_source_pixels
+        )));
+    }
+
+    // truncated or corrupted pictures fail here even when they would be sent as they are.
+    let img = image::load_from_memory_with_format(&buf, format)
+        .map_err(|e| PSNServerError::BadRequest(format!("Can not decode picture: {}", e)))?;
+
+    let max = config.max_image_dimension;
+    let fits = width <= max && height <= max;
+
+    if fits && buf.len() <= config.max_image_size {
+        return Ok(buf);
+    }
+
+    let mut img = if fits {
+        img
+    } else {
+        img.resize(max, max, FilterType::Lanczos3)
+    };
+
+    let output = match format {
+        ImageFormat::Jpeg => ImageOutputFormat::Jpeg(JPEG_QUALITY),
+        _ => ImageOutputFormat::Png,
+    };
+
+    for shrinks in 0..=MAX_SHRINKS {
+        let out = encode(&img, output.clone())?;
+        if out.len() <= config.max_image_size {
+            return Ok(out);
+        }
+
+        if shrinks < MAX_SHRINKS {
+            let (width, height) = img.dimensions();
+            img = img.resize(width / 2, height / 2, FilterType::Lanczos3);
+        }
+    }
+
+    Err(PSNServerError::PayloadTooLarge(format!(
+        "Picture can not be shrunk under {} bytes",
+        config.max_image_size
+    )))
+}
+
+fn encode(img: &DynamicImage, format: ImageOutputFormat) -> Result<Vec<u8>, PSNServerError> {
+    let mut out = Vec::new();
+    img.write_to(&mut out, format)
+        .map_err(|e| PSNServerError::General500(format!("Can not encode picture: {}", e)))?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    fn config() -> MessageConfig {
+        MessageConfig {
+            max_image_size: 64 * 1024,
+            max_image_dimension: 64,
+            max_source_pixels: 1024 * 1024,
+            ..MessageConfig::default()
+        }
+    }
+
+    // noise doesn't compress so the encoded size follows the dimensions.
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut seed = 1u32;
+        let img = RgbImage::from_fn(width, height, |_, _| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let [r, g, b, _] = seed.to_be_bytes();
+            Rgb([r, g, b])
+        });
+        encode(&DynamicImage::ImageRgb8(img), ImageOutputFormat::Png).unwrap()
+    }
+
+    fn dimensions(buf: &[u8]) -> (u32, u32) {
+        image::load_from_memory(buf).unwrap().dimensions()
+    }
+
+    #[test]
+    fn passthrough() {
+        let buf = png(32, 16);
+        assert_eq!(prepare_picture(buf.clone(), &config()).unwrap(), buf);
+    }
+
+    #[test]
+    fn downscale() {
+        let out = prepare_picture(png(256, 128), &config()).unwrap();
+        assert_eq!(dimensions(&out), (64, 32));
+        assert_eq!(image::guess_format(&out).unwrap(), ImageFormat::Png);
+    }
+
+    #[test]
+    fn shrink_to_size() {
+        let config = MessageConfig {
+            max_image_size: 4 * 1024,
+            ..config()
+        };
+
+        // 64x64 noise is about 12KB. It only fits once halved.
+        let out = prepare_picture(png(64, 64), &config).unwrap();
+        assert!(out.len() <= config.max_image_size);
+        assert!(dimensions(&out).0 < 64);
+    }
+
+    #[test]
+    fn can_not_shrink() {
+        let config = MessageConfig {
+            max_image_size: 16,
+            ..config()
+        };
+
+        assert!(matches!(
+            prepare_picture(png(64, 64), &config),
+            Err(PSNServerError::PayloadTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn too_many_pixels() {
+        let config = MessageConfig {
+            max_source_pixels: 100,
+            ..config()
+        };
+
+        assert!(matches!(
+            prepare_picture(png(32, 32), &config),
+            Err(PSNServerError::PayloadTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn bad_format() {
+        assert!(matches!(
+            prepare_picture(b"not a picture".to_vec(), &config()),
+            Err(PSNServerError::BadRequest(_))
+        ));
+
+        // the header is fine but the data is cut off.
+        let mut buf = png(32, 16);
+        buf.truncate(buf.len() / 2);
+        assert!(matches!(
+            prepare_picture(buf, &config()),
+            Err(PSNServerError::BadRequest(_))
+        ));
+    }
+}
@@ -0,0 +1,381 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::api_keys::ApiKey;
+use crate::config::{MessageConfig, RateLimiterConfig};
+
+/// Fixed window rate limiter shared by all workers.
+///
+/// Every request is counted against its route policy. Requests carrying a valid api key are
+/// identified by the key and additionally counted against the quota configured for its name. Requests with the
+/// admin token skip the limiter entirely.
+#[derive(Clone)]
+pub struct SharedRateLimiter(Arc<RateLimiterInner>);
+
+struct RateLimiterInner {
+    config: RateLimiterConfig,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+struct Bucket {
+    count: usize,
+    window_start: Instant,
+    interval: Duration,
+}
+
+impl Bucket {
+    fn is_expired(&self, now: Instant) -> bool {
+        now.duration_since(self.window_start) >= self.interval
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Policy {
+    max_requests: usize,
+    interval: Duration,
+}
+
+/// The state of the bucket that rejected a request.
+#[derive(Debug)]
+pub struct Throttled {
+    pub limit: usize,
+    // seconds until the window resets.
+    pub reset: u64,
+}
+
+impl SharedRateLimiter {
+    pub fn new(config: RateLimiterConfig) -> Self {
+        Self(Arc::new(RateLimiterInner {
+            config,
+            buckets: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.0.config.enabled
+    }
+
+    pub fn recycle_interval(&self) -> Duration {
+        Duration::from_secs(self.0.config.recycle_interval)
+    }
+
+    /// Count one request. `key` is the verified api key of the request and `addr` is the client
+    /// address used when no api key is presented.
+    pub fn check(&self, path: &str, key: Option<&ApiKey>, addr: &str) -> Result<(), Throttled> {
+        let config = &self.0.config;
+
+        // quotas are configured by key name and counted for every key.
+        let quota = key.and_then(|key| config.keys.iter().find(|q| q.name == key.name));
+
+        let identity = match key {
+            Some(key) => format!("key:{}", key.id),
+            None => format!("addr:{}", addr),
+        };
+
+        let mut checks = Vec::with_capacity(2);
+
+        match config.routes.iter().find(|r| r.path == path) {
+            Some(route) => checks.push((
+                format!("{}:{}", identity, route.path),
+                Policy {
+                    max_requests: route.max_requests,
+                    interval: Duration::from_secs(route.interval),
+                },
+                1,
+            )),
+            // key quota is the default policy for requests with api key.
+            None if quota.is_none() => checks.push((
+                format!("{}:{}", identity, path),
+                Policy {
+                    max_requests: config.max_requests,
+                    interval: Duration::from_secs(config.interval),
+                },
+                1,
+            )),
+            None => {}
+        }
+
+        if let Some(quota) = quota {
+            checks.push((
+                identity,
+                Policy {
+                    max_requests: quota.max_requests,
+                    interval: Duration::from_secs(quota.interval),
+                },
+                1,
+            ));
+        }
+
+        self.count(checks)
+    }
+
+    /// Count one message against the throttles of its sender and every recipient. The sender is
+    /// counted once per recipient.
+    ///
+    /// Message throttles work whether the limiter is enabled or not.
+    pub fn check_message(
+        &self,
+        config: &MessageConfig,
+        sender: &str,
+        recipients: &[String],
+    ) -> Result<(), Throttled> {
+        let mut checks = Vec::with_capacity(recipients.len() + 1);
+
+        if config.recipient_max_messages > 0 {
+            for recipient in recipients.iter() {
+                checks.push((
+                    format!("message:to:{}", recipient.to_lowercase()),
+                    Policy {
+                        max_requests: config.recipient_max_messages,
+                        interval: Duration::from_secs(config.recipient_interval),
+                    },
+                    1,
+                ));
+            }
+        }
+
+        if config.sender_max_messages > 0 {
+            checks.push((
+                format!("message:from:{}", sender),
+                Policy {
+                    max_requests: config.sender_max_messages,
+                    interval: Duration::from_secs(config.sender_interval),
+                },
+                recipients.len(),
+            ));
+        }
+
+        self.count(checks)
+    }
+
+    // every check is a bucket key, its policy and how many requests to count.
+    fn count(&self, checks: Vec<(String, Policy, usize)>) -> Result<(), Throttled> {
+        let now = Instant::now();
+        let mut buckets = self.0.buckets.lock().unwrap();
+
+        // check all buckets before counting so a rejected request does not use up any quota.
+        for (bucket_key, policy, cost) in checks.iter() {
+            let (count, reset) = match buckets.get(bucket_key) {
+                Some(bucket) if !bucket.is_expired(now) => {
+                    let elapsed = now.duration_since(bucket.window_start);
+                    let reset = bucket.interval.checked_sub(elapsed).unwrap_or_default();
+                    (bucket.count, reset)
+                }
+                _ => (0, policy.interval),
+            };
+
+            if count + cost > policy.max_requests {
+                return Err(Throttled {
+                    limit: policy.max_requests,
+                    reset: reset.as_secs().max(1),
+                });
+            }
+        }
+
+        for (bucket_key, policy, cost) in checks.into_iter() {
+            let bucket = buckets.entry(bucket_key).or_insert(Bucket {
+                count: 0,
+                window_start: now,
+                interval: policy.interval,
+            });
+
+            if bucket.is_expired(now) {
+                bucket.count = 0;
+                bucket.window_start = now;
+                bucket.interval = policy.interval;
+            }
+
+            bucket.count += cost;
+        }
+
+        Ok(())
+    }
+
+    // remove all buckets with their window passed.
+    pub fn recycle(&self) {
+        let now = Instant::now();
+        self.0
+            .buckets
+            .lock()
+            .unwrap()
+            .retain(|_, bucket| !bucket.is_expired(now));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api_keys::SharedApiKeys;
+    use crate::config::{ApiKeysConfig, KeyLimitConfig, RouteLimitConfig};
+
+    fn limiter() -> SharedRateLimiter {
+        SharedRateLimiter::new(RateLimiterConfig {
+            enabled: true,
+            max_requests: 3,
+            interval: 60,
+            routes: vec![RouteLimitConfig {
+                path: String::from("/message"),
+                max_requests: 1,
+                interval: 60,
+            }],
+            keys: vec![KeyLimitConfig {
+                name: String::from("some_key"),
+                max_requests: 2,
+                interval: 60,
+            }],
+            ..RateLimiterConfig::default()
+        })
+    }
+
+    fn api_key(name: &str) -> ApiKey {
+        let config = ApiKeysConfig {
+            path: None,
+            ..ApiKeysConfig::default()
+        };
+        let keys = SharedApiKeys::new(&config, &MessageConfig::default()).unwrap();
+        keys.create(name.into(), Vec::new(), None).unwrap().0
+    }
+
+    // move the window of every bucket back so it's passed.
+    fn expire_windows(limiter: &SharedRateLimiter) {
+        for bucket in limiter.0.buckets.lock().unwrap().values_mut() {
+            bucket.window_start = Instant::now()
+                .checked_sub(bucket.interval + Duration::from_secs(1))
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn default_policy() {
+        let limiter = limiter();
+
+        for _ in 0..3 {
+            assert!(limiter.check("/psn", None, "1.1.1.1").is_ok());
+        }
+        let throttled = limiter.check("/psn", None, "1.1.1.1").unwrap_err();
+        assert_eq!(throttled.limit, 3);
+        assert!(throttled.reset > 0 && throttled.reset <= 60);
+
+        // every address and path has its own bucket.
+        assert!(limiter.check("/psn", None, "2.2.2.2").is_ok());
+        assert!(limiter.check("/psn/store", None, "1.1.1.1").is_ok());
+    }
+
+    #[test]
+    fn route_policy() {
+        let limiter = limiter();
+
+        assert!(limiter.check("/message", None, "1.1.1.1").is_ok());
+        assert_eq!(
+            limiter
+                .check("/message", None, "1.1.1.1")
+                .unwrap_err()
+                .limit,
+            1
+        );
+    }
+
+    #[test]
+    fn key_quota() {
+        let limiter = limiter();
+        let key = api_key("some_key");
+
+        assert!(limiter.check("/psn", Some(&key), "1.1.1.1").is_ok());
+        // the key is counted wherever it comes from.
+        assert!(limiter.check("/psn", Some(&key), "2.2.2.2").is_ok());
+        assert_eq!(
+            limiter
+                .check("/psn", Some(&key), "3.3.3.3")
+                .unwrap_err()
+                .limit,
+            2
+        );
+
+        // every key with the name has its own quota.
+        assert!(limiter
+            .check("/psn", Some(&api_key("some_key")), "1.1.1.1")
+            .is_ok());
+
+        // keys without a quota are counted by the default policy.
+        let other = api_key("other_key");
+        for _ in 0..3 {
+            assert!(limiter.check("/psn", Some(&other), "1.1.1.1").is_ok());
+        }
+        assert_eq!(
+            limiter
+                .check("/psn", Some(&other), "1.1.1.1")
+                .unwrap_err()
+                .limit,
+            3
+        );
+    }
+
+    #[test]
+    fn rejected_request_uses_no_quota() {
+        let limiter = limiter();
+        let key = api_key("some_key");
+
+        // route limit rejects the second call before the key quota is counted.
+        assert!(limiter.check("/message", Some(&key), "1.1.1.1").is_ok());
+        assert!(limiter.check("/message", Some(&key), "1.1.1.1").is_err());
+        assert!(limiter.check("/psn", Some(&key), "1.1.1.1").is_ok());
+        assert!(limiter.check("/psn", Some(&key), "1.1.1.1").is_err());
+    }
+
+    #[test]
+    fn window_reset() {
+        let limiter = limiter();
+
+        assert!(limiter.check("/message", None, "1.1.1.1").is_ok());
+        assert!(limiter.check("/message", None, "1.1.1.1").is_err());
+
+        expire_windows(&limiter);
+
+        assert!(limiter.check("/message", None, "1.1.1.1").is_ok());
+        assert!(limiter.check("/message", None, "1.1.1.1").is_err());
+    }
+
+    #[test]
+    fn recycle() {
+        let limiter = limiter();
+
+        limiter.check("/psn", None, "1.1.1.1").unwrap();
+        limiter.check("/message", None, "1.1.1.1").unwrap();
+
+        limiter.recycle();
+        assert_eq!(limiter.0.buckets.lock().unwrap().len(), 2);
+
+        expire_windows(&limiter);
+        limiter.recycle();
+        assert!(limiter.0.buckets.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn message_throttles() {
+        let limiter = limiter();
+        let config = MessageConfig {
+            recipient_max_messages: 1,
+            sender_max_messages: 3,
+            ..MessageConfig::default()
+        };
+        let recipients = |ids: &[&str]| ids.iter().map(|id| String::from(*id)).collect::<Vec<_>>();
+
+        assert!(limiter
+            .check_message(&config, "admin", &recipients(&["Player_A", "player_b"]))
+            .is_ok());
+
+        // recipients are matched case insensitively.
+        assert!(limiter
+            .check_message(&config, "other", &recipients(&["player_a"]))
+            .is_err());
+
+        // the sender has one message left out of 3 and this one goes to two recipients.
+        assert!(limiter
+            .check_message(&config, "admin", &recipients(&["player_c", "player_d"]))
+            .is_err());
+        assert!(limiter
+            .check_message(&config, "admin", &recipients(&["player_c"]))
+            .is_ok());
+    }
+}
@@ -1,37 +1,75 @@
+use std::time::Instant;
+
+use ntex::http::header;
 use ntex::web::{
     self,
-    types::{Json, Query},
+    types::{Json, Path, Payload, Query},
     HttpRequest, HttpResponse,
 };
-use ntex_multipart::Multipart;
-use psn_api_rs::{
-    models::{PSNUser, StoreSearchResult, TrophySet, TrophyTitles},
-    psn::PSN,
-};
+use psn_api_rs::psn::PSN;
+use serde_json::{json, Value};
 
+use crate::accounts::SharedAccounts;
+use crate::api_keys::{Scope, SharedApiKeys};
+use crate::audit::SharedAuditLog;
+use crate::cache::SharedCache;
+use crate::coalescer::Coalescer;
+use crate::config::Config;
 use crate::error::PSNServerError;
 use crate::handler::*;
+use crate::job_store::SharedJobStore;
+use crate::message_store::SharedMessageStore;
+use crate::metrics::SharedMetrics;
 use crate::model::{
-    AdminAuth, AdminQuery, PSNInnerRequest, PSNQuery, SharedGlobalState, SharedMap, SolverRequest,
+    AdminAuth, AdminQuery, ApiKeyRequest, AuditListResponse, AuditQuery, CachePurgeResponse,
+    Caller, HealthResponse, MessageAuth, MessageQuery, PSNInnerRequest, PSNQuery,
+    RotateTokenRequest, SharedGlobalState, SolverRequest, ThreadImageQuery, ThreadQuery,
+    ThreadsQuery,
 };
+use crate::rate_limiter::SharedRateLimiter;
 
 #[web::get("")]
 pub(crate) async fn get_admin(
     req: HttpRequest,
-    _auth: AdminAuth,
+    caller: Caller,
     query: Query<AdminQuery>,
 ) -> Result<HttpResponse, PSNServerError> {
-    match query.into_inner() {
+    let query = query.into_inner();
+    let (action, detail) = match &query {
+        AdminQuery::SolverId { solver_id } => ("solver_id", json!({ "solver_id": solver_id })),
+        AdminQuery::ListSolverJobs => ("list_solver_jobs", json!({})),
+        AdminQuery::StartService => ("start_service", json!({})),
+        AdminQuery::PauseService => ("pause_service", json!({})),
+    };
+
+    let res = admin_query(&req, &caller, query);
+    SharedAuditLog::annotate(&req, &caller.identity(), action, detail, &res);
+    res
+}
+
+fn admin_query(
+    req: &HttpRequest,
+    caller: &Caller,
+    query: AdminQuery,
+) -> Result<HttpResponse, PSNServerError> {
+    match query {
         AdminQuery::SolverId { solver_id } => {
-            let map = req.map();
-            handle_solver_id(map, &solver_id)
+            caller.require(Scope::AdminSolver)?;
+            let store = req.job_store();
+            handle_solver_id(store, &solver_id)
+        }
+        AdminQuery::ListSolverJobs => {
+            caller.require(Scope::AdminSolver)?;
+            handle_list_solver_jobs(req.job_store())
         }
         AdminQuery::StartService => {
-            req.psn().resume_inner();
+            caller.require(Scope::AdminPool)?;
+            req.accounts().resume(req.psn());
             default_200_response()
         }
         AdminQuery::PauseService => {
-            req.psn().pause_inner();
+            caller.require(Scope::AdminPool)?;
+            req.accounts().pause(req.psn());
             default_200_response()
         }
     }
@@ -39,95 +77,500 @@ pub(crate) async fn get_admin(
 
 #[web::post("")]
 pub(crate) async fn post_admin(
-    _auth: AdminAuth,
+    caller: Caller,
     req: HttpRequest,
     solver_req: Json<SolverRequest>,
 ) -> Result<HttpResponse, PSNServerError> {
-    let api_key = req.api_key();
-    let map = req.map().clone();
     let solver_req = solver_req.into_inner();
-    let users = solver_req.accounts;
+    let detail = json!({
+        "emails": solver_req.accounts.iter().map(|a| &a.email).collect::<Vec<_>>(),
+        "auto_install": solver_req.auto_install,
+    });
+
+    let res = match caller.require(Scope::AdminSolver) {
+        Ok(_) => {
+            let config = &req.config().solver;
+            let store = req.job_store().clone();
+            let users = solver_req.accounts;
+
+            let auto_install = if solver_req.auto_install {
+                Some((req.psn().clone(), req.accounts().clone()))
+            } else {
+                None
+            };
+
+            let metrics = req.metrics().clone();
+
+            handle_post_admin(config, store, users, auto_install, metrics).await
+        }
+        Err(e) => Err(e),
+    };
+
+    SharedAuditLog::annotate(&req, &caller.identity(), "start_solver_job", detail, &res);
+    res
+}
+
+#[web::delete("/solver/{solver_id}")]
+pub(crate) async fn delete_solver_job(
+    caller: Caller,
+    req: HttpRequest,
+    solver_id: Path<String>,
+) -> Result<HttpResponse, PSNServerError> {
+    let res = caller
+        .require(Scope::AdminSolver)
+        .and_then(|_| handle_delete_solver_job(req.job_store(), &solver_id));
 
-    handle_post_admin(api_key, map, users).await
+    let detail = json!({ "solver_id": solver_id.as_str() });
+    SharedAuditLog::annotate(&req, &caller.identity(), "delete_solver_job", detail, &res);
+    res
 }
 
 #[web::post("/npsso")]
 pub(crate) async fn set_npsso(
-    _auth: AdminAuth,
+    caller: Caller,
     req: HttpRequest,
     npsso: Json<PSNInnerRequest>,
 ) -> Result<HttpResponse, PSNServerError> {
-    let npsso = npsso.into_inner().psn_inners;
-    let psn = req.psn();
+    let npsso = npsso.into_inner();
+    let detail = json!({
+        "emails": npsso.psn_inners.iter().map(|i| &i.email).collect::<Vec<_>>(),
+        "replace": npsso.replace,
+    });
+
+    let res = match caller.require(Scope::AdminPool) {
+        Ok(_) => handle_set_npsso(npsso.psn_inners, npsso.replace, req.psn(), req.accounts()).await,
+        Err(e) => Err(e),
+    };
+
+    SharedAuditLog::annotate(&req, &caller.identity(), "set_npsso", detail, &res);
+    res
+}
+
+#[web::get("/accounts")]
+pub(crate) async fn list_accounts(
+    caller: Caller,
+    req: HttpRequest,
+) -> Result<HttpResponse, PSNServerError> {
+    caller.require(Scope::AdminPool)?;
+
+    handle_list_accounts(req.accounts())
+}
+
+#[web::get("/pool")]
+pub(crate) async fn pool_status(
+    caller: Caller,
+    req: HttpRequest,
+) -> Result<HttpResponse, PSNServerError> {
+    caller.require(Scope::AdminPool)?;
+
+    Ok(HttpResponse::Ok().json(&req.accounts().report()))
+}
+
+#[web::delete("/cache/{online_id}")]
+pub(crate) async fn purge_cache(
+    caller: Caller,
+    req: HttpRequest,
+    online_id: Path<String>,
+) -> Result<HttpResponse, PSNServerError> {
+    let res = caller.require(Scope::AdminPool).map(|_| {
+        HttpResponse::Ok().json(&CachePurgeResponse {
+            status: 200,
+            purged: req.cache().purge(&online_id),
+        })
+    });
 
-    handle_set_npsso(npsso, psn).await
+    let detail = json!({ "online_id": online_id.as_str() });
+    SharedAuditLog::annotate(&req, &caller.identity(), "purge_cache", detail, &res);
+    res
+}
+
+#[web::delete("/accounts/{email}")]
+pub(crate) async fn remove_account(
+    caller: Caller,
+    req: HttpRequest,
+    email: Path<String>,
+) -> Result<HttpResponse, PSNServerError> {
+    let res = caller
+        .require(Scope::AdminPool)
+        .and_then(|_| handle_remove_account(&email, req.psn(), req.accounts()));
+
+    let detail = json!({ "email": email.as_str() });
+    SharedAuditLog::annotate(&req, &caller.identity(), "remove_account", detail, &res);
+    res
 }
 
 #[web::get("/")]
 pub(crate) async fn psn_request(
+    caller: Caller,
     req: HttpRequest,
     query: Query<PSNQuery>,
 ) -> Result<HttpResponse, PSNServerError> {
-    let psn = req.psn();
+    let query = query.into_inner();
+    let started = Instant::now();
 
-    match query.into_inner() {
-        PSNQuery::Profile { online_id } => {
-            let res = psn.get_profile::<PSNUser>(&online_id).await?;
-            psn_request_response(res)
-        }
-        PSNQuery::Titles { online_id, offset } => {
-            let offset = offset.parse::<u32>().unwrap_or(0);
-            let res = psn.get_titles::<TrophyTitles>(&online_id, offset).await?;
-            psn_request_response(res)
+    let res = psn_query(&caller, &req, &query).await;
+
+    let status = match res.as_ref() {
+        Ok(res) => res.status().as_u16(),
+        Err(e) => e.status_code().as_u16(),
+    };
+    req.metrics()
+        .observe_request(query.name(), status, started.elapsed());
+    res
+}
+
+async fn psn_query(
+    caller: &Caller,
+    req: &HttpRequest,
+    query: &PSNQuery,
+) -> Result<HttpResponse, PSNServerError> {
+    // queries stay open to anonymous callers unless api keys are required.
+    let anonymous = matches!(caller, Caller::Anonymous);
+    if !anonymous || req.config().api_keys.require_for_queries {
+        match query.scope() {
+            Some(scope) => caller.require(scope)?,
+            None if anonymous => return Err(PSNServerError::Authorization),
+            None => {}
         }
-        PSNQuery::TrophySet {
-            online_id,
-            np_communication_id,
-        } => {
-            let res = psn
-                .get_trophy_set::<TrophySet>(&online_id, &np_communication_id)
+    }
+    let cache = req.cache();
+    let metrics = req.metrics();
+
+    let cached = cache.get(query);
+    if req.config().cache.enabled {
+        metrics.cache_lookup(cached.is_some());
+    }
+
+    let entry = match cached {
+        Some(entry) => entry,
+        None => {
+            let psn = req.psn().clone();
+            let metrics = metrics.clone();
+            let q = query.clone();
+            let body = req
+                .coalescer()
+                .run(query.cache_key(), async move {
+                    let res = handle_psn_query(&psn, &q).await;
+                    // coalesced requests share one upstream call so it's counted once.
+                    if let Err(PSNServerError::PSN(kind, _)) = res.as_ref() {
+                        metrics.upstream_error(*kind);
+                    }
+                    res
+                })
                 .await?;
-            psn_request_response(res)
+            cache.insert(query, body)
         }
-        PSNQuery::Store {
-            language,
-            region,
-            name,
-            age,
-        } => {
-            let res = psn
-                .search_store_items::<StoreSearchResult>(&language, &region, &age, &name)
-                .await?;
+    };
 
-            psn_request_response(res)
-        }
+    Ok(cached_response(req, &entry))
+}
+
+// threads are read from PSN outside of the coalesced queries so their errors are counted here.
+fn count_upstream_error<T>(req: &HttpRequest, res: &Result<T, PSNServerError>) {
+    if let Err(PSNServerError::PSN(kind, _)) = res {
+        req.metrics().upstream_error(*kind);
     }
 }
 
+#[web::get("/healthz")]
+pub(crate) async fn healthz() -> Result<HttpResponse, PSNServerError> {
+    Ok(HttpResponse::Ok().json(&HealthResponse {
+        status: 200,
+        alive: true,
+    }))
+}
+
+#[web::get("/readyz")]
+pub(crate) async fn readyz(req: HttpRequest) -> Result<HttpResponse, PSNServerError> {
+    handle_readyz(req.http_client(), req.accounts(), &req.config().health).await
+}
+
+#[web::get("/metrics")]
+pub(crate) async fn metrics(req: HttpRequest) -> Result<HttpResponse, PSNServerError> {
+    if !req.config().metrics.enabled {
+        return Err(PSNServerError::NotFound("metrics".into()));
+    }
+
+    handle_metrics(
+        req.metrics(),
+        req.accounts(),
+        req.job_store(),
+        &req.config().solver,
+    )
+}
+
 pub(crate) async fn psn_message_request(
+    auth: MessageAuth,
     req: HttpRequest,
-    payload: Multipart,
+    query: Query<MessageQuery>,
+    payload: Payload,
 ) -> Result<HttpResponse, PSNServerError> {
-    handle_message(req, payload);
-    default_200_response()
+    let mut detail = json!({ "async": query.is_async });
+
+    let res = send_message_request(&auth, &req, query.is_async, payload, &mut detail).await;
+
+    SharedAuditLog::annotate(&req, &auth.sender, "send_message", detail, &res);
+    res
+}
+
+async fn send_message_request(
+    auth: &MessageAuth,
+    req: &HttpRequest,
+    is_async: bool,
+    payload: Payload,
+    detail: &mut Value,
+) -> Result<HttpResponse, PSNServerError> {
+    let config = &req.config().message;
+
+    // reject early when client tells the size. Otherwise the limit is checked while reading.
+    let content_length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if content_length
+        .map(|len| len > config.max_payload_size)
+        .unwrap_or(false)
+    {
+        return Err(PSNServerError::PayloadTooLarge(
+            "Message is too large".into(),
+        ));
+    }
+
+    let mut draft = parse_message(req, payload, config).await?;
+
+    detail["recipients"] = json!(draft.online_ids);
+    detail["text"] = json!(draft.text.is_some());
+    detail["picture"] = json!(draft.image.is_some());
+
+    if let Some(online_id) = draft
+        .online_ids
+        .iter()
+        .find(|id| !config.allows_recipient(id))
+    {
+        return Err(PSNServerError::Forbidden(format!(
+            "Sending message to {} is not allowed",
+            online_id
+        )));
+    }
+    req.rate_limiter()
+        .check_message(config, &auth.sender, &draft.online_ids)?;
+
+    prepare_message_picture(&mut draft, config).await?;
+
+    let psn = req.psn().clone();
+    let store = req.message_store().clone();
+
+    handle_message(
+        psn,
+        store,
+        auth.sender.clone(),
+        draft,
+        config.send_interval(),
+        is_async,
+    )
+    .await
+}
+
+#[web::get("/message/{message_id}")]
+pub(crate) async fn message_status(
+    auth: MessageAuth,
+    req: HttpRequest,
+    message_id: Path<String>,
+) -> Result<HttpResponse, PSNServerError> {
+    handle_message_status(req.message_store(), &message_id, &auth)
+}
+
+#[web::get("/threads")]
+pub(crate) async fn message_threads(
+    _auth: MessageAuth,
+    req: HttpRequest,
+    query: Query<ThreadsQuery>,
+) -> Result<HttpResponse, PSNServerError> {
+    let res = handle_message_threads(
+        req.http_client(),
+        req.accounts(),
+        query.email.as_deref(),
+        query.offset,
+    )
+    .await;
+
+    count_upstream_error(&req, &res);
+    res
+}
+
+#[web::get("/threads/{thread_id}")]
+pub(crate) async fn message_thread(
+    _auth: MessageAuth,
+    req: HttpRequest,
+    thread_id: Path<String>,
+    query: Query<ThreadQuery>,
+) -> Result<HttpResponse, PSNServerError> {
+    let res = handle_message_thread(
+        req.http_client(),
+        req.accounts(),
+        query.email.as_deref(),
+        &thread_id,
+        query.offset,
+        query.limit,
+    )
+    .await;
+
+    count_upstream_error(&req, &res);
+    res
+}
+
+#[web::get("/threads/{thread_id}/image")]
+pub(crate) async fn message_thread_image(
+    _auth: MessageAuth,
+    req: HttpRequest,
+    thread_id: Path<String>,
+    query: Query<ThreadImageQuery>,
+) -> Result<HttpResponse, PSNServerError> {
+    let res = handle_thread_image(
+        req.http_client(),
+        req.accounts(),
+        query.email.as_deref(),
+        &thread_id,
+        &query.url,
+    )
+    .await;
+
+    count_upstream_error(&req, &res);
+    res
+}
+
+#[web::post("/keys")]
+pub(crate) async fn create_api_key(
+    _auth: AdminAuth,
+    req: HttpRequest,
+    key_req: Json<ApiKeyRequest>,
+) -> Result<HttpResponse, PSNServerError> {
+    let key_req = key_req.into_inner();
+    let detail = json!({
+        "name": key_req.name,
+        "scopes": key_req.scopes,
+        "expires_in": key_req.expires_in,
+    });
+
+    let res = handle_create_api_key(req.api_keys(), key_req);
+    SharedAuditLog::annotate(&req, "admin", "create_api_key", detail, &res);
+    res
+}
+
+#[web::get("/keys")]
+pub(crate) async fn list_api_keys(
+    _auth: AdminAuth,
+    req: HttpRequest,
+) -> Result<HttpResponse, PSNServerError> {
+    handle_list_api_keys(req.api_keys())
+}
+
+#[web::delete("/keys/{key_id}")]
+pub(crate) async fn revoke_api_key(
+    _auth: AdminAuth,
+    req: HttpRequest,
+    key_id: Path<String>,
+) -> Result<HttpResponse, PSNServerError> {
+    let res = handle_revoke_api_key(req.api_keys(), &key_id);
+
+    let detail = json!({ "key_id": key_id.as_str() });
+    SharedAuditLog::annotate(&req, "admin", "revoke_api_key", detail, &res);
+    res
+}
+
+#[web::post("/token")]
+pub(crate) async fn rotate_admin_token(
+    _auth: AdminAuth,
+    req: HttpRequest,
+    token_req: Json<RotateTokenRequest>,
+) -> Result<HttpResponse, PSNServerError> {
+    let token_req = token_req.into_inner();
+    let detail = json!({ "grace_period": token_req.grace_period });
+
+    let res = handle_rotate_admin_token(req.state(), token_req);
+    SharedAuditLog::annotate(&req, "admin", "rotate_admin_token", detail, &res);
+    res
+}
+
+#[web::get("/audit")]
+pub(crate) async fn query_audit_log(
+    _auth: AdminAuth,
+    req: HttpRequest,
+    query: Query<AuditQuery>,
+) -> Result<HttpResponse, PSNServerError> {
+    Ok(HttpResponse::Ok().json(&AuditListResponse {
+        status: 200,
+        entries: req.audit().query(&query),
+    }))
 }
 
 pub trait FromAppData {
+    fn state(&self) -> &SharedGlobalState;
     fn psn(&self) -> &PSN;
-    fn api_key(&self) -> &str;
-    fn map(&self) -> &SharedMap;
+    fn config(&self) -> &Config;
+    fn job_store(&self) -> &SharedJobStore;
+    fn accounts(&self) -> &SharedAccounts;
+    fn cache(&self) -> &SharedCache;
+    fn coalescer(&self) -> &Coalescer;
+    fn message_store(&self) -> &SharedMessageStore;
+    fn rate_limiter(&self) -> &SharedRateLimiter;
+    fn api_keys(&self) -> &SharedApiKeys;
+    fn audit(&self) -> &SharedAuditLog;
+    fn metrics(&self) -> &SharedMetrics;
+    fn http_client(&self) -> &reqwest::Client;
 }
 
 impl FromAppData for HttpRequest {
+    fn state(&self) -> &SharedGlobalState {
+        self.app_data::<SharedGlobalState>().unwrap()
+    }
+
     fn psn(&self) -> &PSN {
         self.app_data::<PSN>().unwrap()
     }
 
-    fn api_key(&self) -> &str {
-        self.app_data::<SharedGlobalState>().unwrap().api_key()
+    fn config(&self) -> &Config {
+        self.app_data::<Config>().unwrap()
+    }
+
+    fn job_store(&self) -> &SharedJobStore {
+        self.app_data::<SharedJobStore>().unwrap()
+    }
+
+    fn accounts(&self) -> &SharedAccounts {
+        self.app_data::<SharedAccounts>().unwrap()
+    }
+
+    fn cache(&self) -> &SharedCache {
+        self.app_data::<SharedCache>().unwrap()
+    }
+
+    fn coalescer(&self) -> &Coalescer {
+        self.app_data::<Coalescer>().unwrap()
+    }
+
+    fn message_store(&self) -> &SharedMessageStore {
+        self.app_data::<SharedMessageStore>().unwrap()
+    }
+
+    fn rate_limiter(&self) -> &SharedRateLimiter {
+        self.app_data::<SharedRateLimiter>().unwrap()
+    }
+
+    fn api_keys(&self) -> &SharedApiKeys {
+        self.app_data::<SharedApiKeys>().unwrap()
+    }
+
+    fn audit(&self) -> &SharedAuditLog {
+        self.app_data::<SharedAuditLog>().unwrap()
+    }
+
+    fn metrics(&self) -> &SharedMetrics {
+        self.app_data::<SharedMetrics>().unwrap()
     }
 
-    fn map(&self) -> &SharedMap {
-        self.app_data::<SharedMap>().unwrap()
+    fn http_client(&self) -> &reqwest::Client {
+        self.app_data::<reqwest::Client>().unwrap()
     }
 }
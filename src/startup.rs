@@ -1,25 +1,61 @@
-use std::future::Future;
-use std::pin::Pin;
 use std::time::Duration;
 
+use log::{error, info, warn};
 use ntex::http::header;
 use ntex::server::openssl::SslAcceptorBuilder;
-use ntex::web::dev::WebRequest;
 use ntex_cors::CorsFactory;
-use ntex_ratelimiter::{DefaultIdentifier, Filter, FilterResult, RateLimiter};
 use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
-use psn_api_rs::{psn::PSN, traits::PSNRequest};
+use psn_api_rs::psn::PSN;
+
+use crate::accounts::SharedAccounts;
+use crate::api_keys::SharedApiKeys;
+use crate::audit::SharedAuditLog;
+use crate::cache::SharedCache;
+use crate::config::{
+    ApiKeysConfig, AuditConfig, CacheConfig, CredentialsConfig, JobStoreConfig, MessageAuthMode,
+    MessageConfig, RateLimiterConfig, RefresherConfig, TlsConfig,
+};
+use crate::credentials::CredentialStore;
+use crate::error::PSNServerError;
+use crate::job_store::SharedJobStore;
+use crate::message_store::SharedMessageStore;
+use crate::metrics::SharedMetrics;
+use crate::model::{unix_timestamp, SharedGlobalState};
+use crate::rate_limiter::SharedRateLimiter;
+
+pub fn global_builder(admin_token: &str) -> SharedGlobalState {
+    SharedGlobalState::new(admin_token)
+}
 
-use crate::model::{SharedGlobalState, SharedMap};
+pub fn api_keys_builder(
+    config: &ApiKeysConfig,
+    message: &MessageConfig,
+) -> Result<SharedApiKeys, PSNServerError> {
+    if let MessageAuthMode::Key = message.auth {
+        warn!(
+            "message.keys are deprecated. Create api keys with send:message scope at POST /admin/keys instead"
+        );
+    }
 
-pub fn global_builder(admin_token: String, api_key: String) -> (SharedGlobalState, SharedMap) {
-    let state = SharedGlobalState::new(admin_token, api_key);
-    let map = SharedMap::new();
+    SharedApiKeys::new(config, message)
+}
+
+pub fn audit_builder(config: &AuditConfig) -> Result<SharedAuditLog, PSNServerError> {
+    SharedAuditLog::new(config)
+}
 
-    (state, map)
+pub fn job_store_builder(
+    config: &JobStoreConfig,
+    credentials: &CredentialsConfig,
+) -> Result<SharedJobStore, PSNServerError> {
+    SharedJobStore::new(config, credentials)
 }
 
-pub fn ssl_builder(key_path: String, cert_path: String) -> SslAcceptorBuilder {
+// safe to unwrap as paths are checked when validating config.
+pub fn ssl_builder(config: &TlsConfig) -> SslAcceptorBuilder {
+    let key_path = config.key_path.as_ref().unwrap();
+    let cert_path = config.cert_path.as_ref().unwrap();
+
     let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
 
     builder
@@ -39,6 +75,30 @@ pub async fn psn_builder() -> PSN {
     psn
 }
 
+pub fn accounts_builder(
+    psn: &PSN,
+    credentials: &CredentialsConfig,
+) -> Result<SharedAccounts, PSNServerError> {
+    let accounts = SharedAccounts::new(CredentialStore::new(credentials)?);
+
+    // a broken credentials file should not stop the service. Accounts can be added again by admin.
+    match accounts.restore(psn) {
+        Ok(0) => Ok(accounts),
+        Ok(len) => {
+            info!("Restored {} PSN accounts from credentials file", len);
+            Ok(accounts)
+        }
+        Err(e) => {
+            // saving the pool now would overwrite the stored accounts that could not be read.
+            error!(
+                "{}. Accounts are not persisted until the credentials file or key is fixed",
+                e
+            );
+            Ok(SharedAccounts::new(None))
+        }
+    }
+}
+
 pub fn cors_builder(cors_origin: &str) -> CorsFactory {
     let mut cors = ntex_cors::Cors::new();
 
@@ -46,7 +106,7 @@ pub fn cors_builder(cors_origin: &str) -> CorsFactory {
         cors = cors.allowed_origin(cors_origin);
     }
 
-    cors.allowed_methods(vec!["GET", "POST"])
+    cors.allowed_methods(vec!["GET", "POST", "DELETE"])
         .allowed_headers(vec![
             header::AUTHORIZATION,
             header::ACCEPT,
@@ -56,51 +116,72 @@ pub fn cors_builder(cors_origin: &str) -> CorsFactory {
         .finish()
 }
 
-#[derive(Clone)]
-pub struct MyFilter(String);
-
-impl<E> Filter<E> for MyFilter {
-    fn filter(&self, req: &WebRequest<E>) -> Pin<Box<dyn Future<Output = FilterResult>>> {
-        let token = req
-            .headers()
-            .get("Authorization")
-            .map(|value| value.to_str().unwrap_or("").to_owned());
-
-        let res = match token {
-            Some(token) => {
-                if token.contains(self.0.as_str()) {
-                    FilterResult::Skip
-                } else {
-                    FilterResult::Continue
-                }
-            }
-            None => FilterResult::Continue,
-        };
-
-        Box::pin(async move { res })
-    }
+pub fn cache_builder(config: &CacheConfig) -> SharedCache {
+    SharedCache::new(config.clone())
 }
 
-pub fn rate_limiter_builder<E>(auth_token: &str) -> RateLimiter<E, DefaultIdentifier, MyFilter> {
-    RateLimiter::new(DefaultIdentifier, MyFilter(auth_token.into()))
-        .max_requests(60)
-        .interval(Duration::from_secs(3600))
-        .recycle_interval(Duration::from_secs(60))
+// bounds every request made to PSN outside of psn_api_rs so a stalled answer can't hold a worker.
+const HTTP_CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub fn http_client_builder() -> Result<reqwest::Client, PSNServerError> {
+    reqwest::Client::builder()
+        .timeout(HTTP_CLIENT_TIMEOUT)
+        .build()
+        .map_err(|e| PSNServerError::General500(format!("Failed to build http client: {}", e)))
 }
 
-pub fn schedule_refresher(psn: PSN) {
+pub fn metrics_builder() -> SharedMetrics {
+    SharedMetrics::default()
+}
+
+pub fn rate_limiter_builder(config: &RateLimiterConfig) -> SharedRateLimiter {
+    SharedRateLimiter::new(config.clone())
+}
+
+// message throttles use the limiter even when it's disabled so it's always recycled.
+pub fn schedule_rate_limiter_recycle(limiter: SharedRateLimiter) {
+    ntex_rt::spawn(async move {
+        // lifecycle: This loop will go on until the server is exit.
+        loop {
+            ntex_rt::time::delay_for(limiter.recycle_interval()).await;
+            limiter.recycle();
+        }
+    });
+}
+
+pub fn message_store_builder() -> SharedMessageStore {
+    SharedMessageStore::new()
+}
+
+// message results are kept as long as solver jobs.
+pub fn schedule_job_sweeper(
+    store: SharedJobStore,
+    messages: SharedMessageStore,
+    config: &JobStoreConfig,
+) {
+    let ttl = config.ttl().as_secs();
+    let interval = config.sweep_interval();
+
+    ntex_rt::spawn(async move {
+        // lifecycle: This loop will go on until the server is exit.
+        loop {
+            ntex_rt::time::delay_for(interval).await;
+            let before = unix_timestamp().saturating_sub(ttl);
+            let _ = store.remove_finished_before(before);
+            messages.remove_finished_before(before);
+        }
+    });
+}
+
+pub fn schedule_refresher(psn: PSN, accounts: SharedAccounts, config: &RefresherConfig) {
+    let config = config.clone();
+
     ntex_rt::spawn(async move {
         // lifecycle: This loop will go on until the server is exit.
+        // the first run brings back the accounts restored from credentials file.
         loop {
-            ntex_rt::time::delay_for(Duration::from_secs(900)).await;
-            let pool = psn.get_inner();
-            let inner = pool.get().await;
-
-            if let Ok(mut inner) = inner {
-                if let Ok(client) = PSN::new_client() {
-                    let _ = inner.gen_access_from_refresh(&client).await;
-                }
-            }
+            accounts.refresh_due(&psn, &config).await;
+            ntex_rt::time::delay_for(config.interval()).await;
         }
     });
 }